@@ -3,27 +3,79 @@
 
 extern crate arduino_mkrvidor4000 as hal;
 
+use embedded_hal::serial;
 use hal::clock::GenericClockController;
 use hal::entry;
-use hal::gpio::{Floating, Input, IntoFunction, Output, Pa12, Pa13, Pa14, Pa15, PushPull};
+use hal::gpio::{
+    Floating, Input, IntoFunction, Output, Pa12, Pa13, Pa14, Pa15, Pb22, Pb23, PfD, PushPull,
+};
 use hal::pac::{interrupt, CorePeripherals, Peripherals, NVIC};
+use hal::sercom::{PadPin, Sercom5Pad2, Sercom5Pad3, UART5};
+use hal::time::Hertz;
 use hal::usb::usb_device::{bus::UsbBusAllocator, prelude::*};
 use hal::usb::UsbBus;
 
-use usbd_blaster::{Blaster, ALTERA_BLASTER_USB_VID_PID};
+use usbd_blaster::{
+    Blaster, Driven, FlowControl, LineCoding, Parity, Port, ReadOnly, SerialNumber, StopBits, Uart,
+    UartConfig, ALTERA_BLASTER_USB_VID_PID,
+};
 
 // #[link_section = "FLASH_FPGA"]
 // const FLASH_FPGA: [u8; 2 * 1024 * 1024] = [0u8; 2 * 1024 * 1024];
 
+const SERIAL1_BAUD_RATE: u32 = 115_200;
+
+/// Serial1 on the MKR header, bridged to the host as the blaster's UART.
+/// `UartConfig` and `UART5` both come from other crates, so the trait is implemented on a newtype around it.
+struct Serial1(UART5<Sercom5Pad3<Pb23<PfD>>, Sercom5Pad2<Pb22<PfD>>, (), ()>);
+
+impl serial::Read<u8> for Serial1 {
+    type Error = ();
+
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        self.0.read()
+    }
+}
+
+impl serial::Write<u8> for Serial1 {
+    type Error = ();
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+        self.0.write(byte)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), ()> {
+        self.0.flush()
+    }
+}
+
+impl UartConfig for Serial1 {
+    /// The HAL sets the baud rate once, so only 8N1 at about that rate is accepted
+    fn set_line_coding(&mut self, coding: &LineCoding) -> bool {
+        coding.baud_rate > SERIAL1_BAUD_RATE * 31 / 32
+            && coding.baud_rate < SERIAL1_BAUD_RATE * 33 / 32
+            && coding.data_bits == 8
+            && coding.parity == Parity::None
+            && coding.stop_bits == StopBits::One
+            && coding.flow_control == FlowControl::None
+    }
+}
+
+static mut SERIAL_NUMBER: Option<SerialNumber> = None;
 static mut USB_ALLOCATOR: Option<UsbBusAllocator<UsbBus>> = None;
 static mut USB_BLASTER: Option<
     Blaster<
         UsbBus,
-        (),
-        Pa12<Output<PushPull>>,
-        Pa13<Output<PushPull>>,
-        Pa14<Output<PushPull>>,
-        Pa15<Input<Floating>>,
+        Port<
+            (),
+            Driven<Pa12<Output<PushPull>>>,
+            Driven<Pa13<Output<PushPull>>>,
+            Driven<Pa14<Output<PushPull>>>,
+            Pa15<Input<Floating>>,
+        >,
+        ReadOnly,
+        fn() -> u32,
+        Uart<Serial1>,
     >,
 > = None;
 static mut USB_BUS: Option<UsbDevice<UsbBus>> = None;
@@ -47,6 +99,17 @@ fn main() -> ! {
 
     let main_clk = clocks.gclk0();
     let usb_clock = clocks.usb(&main_clk).unwrap();
+    let sercom5_clock = clocks.sercom5_core(&main_clk).unwrap();
+    let serial1 = Serial1(UART5::new(
+        &sercom5_clock,
+        Hertz(SERIAL1_BAUD_RATE),
+        peripherals.SERCOM5,
+        &mut peripherals.PM,
+        (
+            pins.rx.into_pad(&mut pins.port),
+            pins.tx.into_pad(&mut pins.port),
+        ),
+    ));
 
     let allocator = unsafe {
         USB_ALLOCATOR = UsbBusAllocator::new(UsbBus::new(
@@ -59,6 +122,11 @@ fn main() -> ! {
         .into();
         USB_ALLOCATOR.as_ref().unwrap()
     };
+    // Derive the serial number from the MCU unique ID, so that several boards can be plugged into one host
+    let serial_number = unsafe {
+        SERIAL_NUMBER = SerialNumber::from_unique_id(&hal::serial_number()).into();
+        SERIAL_NUMBER.as_ref().unwrap().as_str()
+    };
     unsafe {
         LED = pins
             .led_builtin
@@ -71,11 +139,13 @@ fn main() -> ! {
             pins.fpga_tms.into_push_pull_output(&mut pins.port),
             pins.fpga_tdo.into_floating_input(&mut pins.port),
         )
+        .with_serial_number(serial_number)
+        .with_uart(allocator, Uart::new(serial1))
         .into();
         USB_BUS = UsbDeviceBuilder::new(&allocator, ALTERA_BLASTER_USB_VID_PID)
             .manufacturer("Arduino LLC")
             .product("Arduino MKR Vidor 4000")
-            .serial_number("12345678")
+            .serial_number(serial_number)
             .device_release(0x0400)
             .max_power(500)
             .build()
@@ -92,12 +162,10 @@ fn USB() {
     unsafe {
         USB_BUS.as_mut().map(|usb_dev| {
             USB_BLASTER.as_mut().map(|blaster| {
-                usb_dev.poll(&mut [blaster]);
-                if let Ok(_amount) = blaster.read() {
+                let summary = blaster.poll(usb_dev).unwrap();
+                if summary.received != 0 {
                     LED.as_mut().map(|led| led.toggle());
                 }
-                blaster.handle().unwrap();
-                blaster.write(true).ok();
             });
         });
     };
@@ -0,0 +1,134 @@
+use crate::error::BlasterError;
+
+/// [Shift bit](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L173)
+const BLASTER_STA_SHIFT: u8 = 0x80;
+/// [Read bit](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L171)
+const BLASTER_STA_READ: u8 = 0x40;
+/// [Byte shift count mask](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L174)
+const BLASTER_STA_CNT_MASK: u8 = 0x3f;
+
+/// The engine that carries out the bytes sent by the host for a [`Blaster`](crate::Blaster).
+/// [`Port`](crate::Port) bit-bangs them on GPIO pins; implement this to drive the lines some other way,
+/// such as with DMA, timers, PIO or a soft core, or to simulate a target on the host.
+pub trait JtagBackend {
+    type Error;
+
+    /// Drive the lines as given by a bit-bang byte: TCK, TMS, nCE, nCS, TDI and OE in bits 0 to 5
+    fn bit_bang(&mut self, byte: u8) -> Result<(), BlasterError<Self::Error>>;
+    /// The status read back after a bit-bang byte: TDO in bit 0 and DATAOUT in bit 1
+    fn read_status(&mut self) -> Result<u8, BlasterError<Self::Error>>;
+    /// Clock out a shift-mode byte on TDI, LSB first. If `read`, return the bits sampled on TDO.
+    fn shift_byte(&mut self, byte: u8, read: bool) -> Result<u8, BlasterError<Self::Error>>;
+    /// Clock out a run of shift-mode bytes. If `read`, replace them with the bits sampled on TDO.
+    /// Override this to shift the whole run at once.
+    fn shift_bytes(
+        &mut self,
+        data: &mut [u8],
+        read: bool,
+    ) -> Result<(), BlasterError<Self::Error>> {
+        for byte in data.iter_mut() {
+            *byte = self.shift_byte(*byte, read)?;
+        }
+        Ok(())
+    }
+    /// Park the lines after a USB reset
+    fn reset(&mut self) -> Result<(), Self::Error>;
+
+    /// The TCK frequency limit in Hz, or 0 if there is none
+    fn tck_frequency(&self) -> u32 {
+        0
+    }
+    /// Limit TCK to `hz`, or remove the limit with 0. Returns false if the frequency cannot be set.
+    fn set_tck_frequency(&mut self, _hz: u32) -> bool {
+        false
+    }
+    /// Assert or release nTRST, returning false if it is not connected
+    fn set_ntrst(&mut self, _asserted: bool) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+    /// Assert or release nSRST, returning false if it is not connected
+    fn set_nsrst(&mut self, _asserted: bool) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+    /// Pulse nTRST low, returning false if it is not connected
+    fn pulse_ntrst(&mut self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+    /// Pulse nSRST low, returning false if it is not connected
+    fn pulse_nsrst(&mut self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+}
+
+/// Splits the bytes from the host into bit-bang bytes and runs of shift-mode bytes
+#[derive(Default)]
+pub(crate) struct ByteDecoder {
+    shift_count: u8,
+    read_en: bool,
+}
+
+impl ByteDecoder {
+    /// Handle at most `budget` received bytes, returning how many were handled
+    #[inline]
+    pub fn handle<J: JtagBackend>(
+        &mut self,
+        backend: &mut J,
+        recv_buf: &mut [u8],
+        recv_len: &mut usize,
+        send_buf: &mut [u8],
+        send_len: &mut usize,
+        budget: usize,
+    ) -> Result<usize, BlasterError<J::Error>> {
+        let mut i = 0usize;
+        while i < *recv_len && i < budget && *send_len < send_buf.len() {
+            let d = recv_buf[i];
+            if self.shift_count == 0 {
+                // bit-bang mode (default)
+                self.read_en = (d & BLASTER_STA_READ) != 0;
+                if d & BLASTER_STA_SHIFT != 0 {
+                    // Swap to shift mode for 0 to 63 shifts
+                    self.shift_count = d & BLASTER_STA_CNT_MASK;
+                    // [Record shift register content and send it to the host](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L199)
+                    // if self.read_en {
+                    //     send_buf[*send_len] = self.shift_data;
+                    //     *send_len += 1;
+                    // }
+                } else {
+                    backend.bit_bang(d)?;
+                    if self.read_en {
+                        send_buf[*send_len] = backend.read_status()?;
+                        *send_len += 1;
+                    }
+                }
+                i += 1;
+            } else {
+                // shift-mode, as many bytes at once as are received and fit in the send buffer
+                let mut count = (self.shift_count as usize)
+                    .min(*recv_len - i)
+                    .min(budget - i);
+                if self.read_en {
+                    count = count.min(send_buf.len() - *send_len);
+                }
+                let data = &mut recv_buf[i..i + count];
+                backend.shift_bytes(data, self.read_en)?;
+                if self.read_en {
+                    send_buf[*send_len..*send_len + count].copy_from_slice(data);
+                    *send_len += count;
+                }
+                self.shift_count -= count as u8;
+                i += count;
+            }
+        }
+        if i != 0 {
+            recv_buf.copy_within(i..*recv_len, 0);
+            *recv_len -= i;
+        }
+        Ok(i)
+    }
+
+    /// Go back to bit-bang mode
+    pub fn reset(&mut self) {
+        self.shift_count = 0;
+        self.read_en = false;
+    }
+}
@@ -1,30 +1,110 @@
 use hal::digital::v2::{InputPin, OutputPin};
-use usb_device::{class_prelude::*, control::RequestType};
+use usb_device::{class_prelude::*, control::RequestType, device::UsbDevice};
 
-use crate::class::{BlasterClass, FTDI_MODEM_STA_DUMMY};
-use crate::port::Port;
+use crate::backend::{ByteDecoder, JtagBackend};
+use crate::class::{BlasterClass, Channel, FTDI_MODEM_STA_DUMMY};
+use crate::clock::MillisClock;
+use crate::delay::TckDelay;
+use crate::error::BlasterError;
+use crate::ft245::{
+    checksum, EepromBuilder, EepromStorage, ReadOnly, CHECKSUM_OFFSET, EEPROM_SIZE,
+};
+use crate::pins::{
+    ActiveSerialPins, Driven, MaskedWrite, PassiveSerialPins, Polarity, ResetPins, ReturnClock,
+    TristatePin,
+};
+use crate::port::{Mode, Port};
+use crate::spi::ShiftSpi;
+use crate::uart::{LineCoding, UartBridge, UartChannel};
 
 /// Depending on the underlying USB library (libusb or similar) the OS may send/receive more bytes than declared in the USB endpoint
 /// If this happens to you, please open an issue for this crate on GitHub.
-const BLASTER_WRITE_SIZE: usize = 64;
-const BLASTER_READ_SIZE: usize = 32;
+pub(crate) const BLASTER_WRITE_SIZE: usize = 64;
+pub(crate) const BLASTER_READ_SIZE: usize = 32;
+
+/// When the blaster sends the two modem status bytes to the host without any data
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Heartbeat {
+    /// Whenever the host reads the device
+    Always,
+    /// Whenever the latency timer expires, like a real FT245. Behaves like `Always` without a clock.
+    LatencyTimer,
+    /// Never; the status is only sent along with data
+    OnDemand,
+}
+
+/// How much received data one call to [`Blaster::poll`] may handle
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Budget {
+    /// Every received byte, as long as there is room for the replies
+    Unlimited,
+    /// At most this many received bytes
+    Bytes(usize),
+    /// Received bytes until this many milliseconds have passed. Behaves like `Unlimited` without a clock.
+    Millis(u32),
+}
+
+/// What happened during one call to [`Blaster::poll`]
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct PollSummary {
+    /// Bytes read from the host
+    pub received: usize,
+    /// Received bytes handled
+    pub handled: usize,
+    /// Bytes written to the host, including the modem status
+    pub sent: usize,
+    /// Received bytes still waiting to be handled
+    pub pending: usize,
+}
+
+impl PollSummary {
+    /// Whether there is more work to do, so that [`Blaster::poll`] should be called again soon
+    pub fn is_busy(&self) -> bool {
+        self.pending != 0
+    }
+}
+
+/// Set the TCK frequency limit in Hz, with the low half in the value and the high half in the index; 0 removes the limit.
+/// Not an FT245 request, so it does not clash with any that hosts send.
+pub const BLASTER_VEN_REQ_SET_TCK_FREQ: u8 = 0xA0;
+/// Drive the reset lines selected in the value: bit 0 for nTRST, bit 1 for nSRST.
+/// The index is 0 to release them, 1 to assert them or 2 to pulse them. Rejected if a selected line is not connected.
+pub const BLASTER_VEN_REQ_RESET: u8 = 0xA2;
+/// Reset line bit for nTRST in [`BLASTER_VEN_REQ_RESET`]
+const RESET_NTRST: u16 = 0x0001;
+/// Reset line bit for nSRST in [`BLASTER_VEN_REQ_RESET`]
+const RESET_NSRST: u16 = 0x0002;
+/// Read back the TCK frequency limit in Hz, as four little-endian bytes
+pub const BLASTER_VEN_REQ_GET_TCK_FREQ: u8 = 0xA1;
+
+/// Received bytes handled between clock checks with [`Budget::Millis`]
+const MILLIS_BUDGET_CHUNK: usize = 16;
 
 /// Blaster device class
 pub struct Blaster<
     'a,
     B: UsbBus,
-    E,
-    TDI: OutputPin<Error = E>,
-    TCK: OutputPin<Error = E>,
-    TMS: OutputPin<Error = E>,
-    TDO: InputPin<Error = E>,
+    J: JtagBackend,
+    S: EepromStorage = ReadOnly,
+    C: MillisClock = fn() -> u32,
+    U: UartBridge = (),
 > {
     class: BlasterClass<'a, B>,
-    port: Port<E, TDI, TCK, TMS, TDO>,
+    backend: J,
+    decoder: ByteDecoder,
+    storage: S,
+    clock: Option<C>,
+    /// When the last IN packet was sent, according to the clock
+    last_write_ms: u32,
+    heartbeat: Heartbeat,
+    budget: Budget,
     send_buffer: [u8; BLASTER_WRITE_SIZE],
     send_len: usize,
     recv_buffer: [u8; BLASTER_READ_SIZE],
     recv_len: usize,
+    uart: UartChannel<U>,
+    /// The first error raised while handling a USB reset or control request
+    error: Option<BlasterError<J::Error>>,
 }
 
 impl<
@@ -35,34 +115,218 @@ impl<
         TCK: OutputPin<Error = E>,
         TMS: OutputPin<Error = E>,
         TDO: InputPin<Error = E>,
-    > Blaster<'a, B, E, TDI, TCK, TMS, TDO>
+    > Blaster<'a, B, Port<E, Driven<TDI>, Driven<TCK>, Driven<TMS>, TDO>>
 {
     /// Allocate a Blaster on the USB bus. Takes control of the four JTAG pins.
     /// The JTAG pins can be any pins you want, just make sure you assign them correctly.
+    /// TDI, TCK and TMS are always driven; use [`Blaster::new_tristate`] if they are shared with another device.
     pub fn new(
         alloc: &'a UsbBusAllocator<B>,
         tdi: TDI,
         tck: TCK,
         tms: TMS,
         tdo: TDO,
-    ) -> Blaster<'a, B, E, TDI, TCK, TMS, TDO> {
+    ) -> Self {
+        Blaster::new_tristate(alloc, Driven(tdi), Driven(tck), Driven(tms), tdo)
+    }
+}
+
+impl<
+        'a,
+        B: UsbBus,
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+    > Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO>>
+{
+    /// Allocate a Blaster on the USB bus. Takes control of the four JTAG pins.
+    /// TDI, TCK and TMS are released to high impedance whenever the host clears the output enable (OE) bit,
+    /// for boards where these pins are shared with another device.
+    pub fn new_tristate(
+        alloc: &'a UsbBusAllocator<B>,
+        tdi: TDI,
+        tck: TCK,
+        tms: TMS,
+        tdo: TDO,
+    ) -> Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO>> {
+        Blaster::from_backend(alloc, Port::new(tdi, tck, tms, tdo))
+    }
+}
+
+impl<'a, B: UsbBus, J: JtagBackend> Blaster<'a, B, J> {
+    /// Allocate a Blaster on the USB bus that carries out the bytes from the host with `backend` instead of bit-banging GPIO pins.
+    pub fn from_backend(alloc: &'a UsbBusAllocator<B>, backend: J) -> Blaster<'a, B, J> {
         let mut blaster = Blaster {
             class: BlasterClass::new(alloc, BLASTER_WRITE_SIZE as u16, BLASTER_READ_SIZE as u16),
-            port: Port::new(tdi, tck, tms, tdo),
+            backend,
+            decoder: ByteDecoder::default(),
+            storage: ReadOnly,
+            clock: None,
+            last_write_ms: 0,
+            heartbeat: Heartbeat::Always,
+            budget: Budget::Unlimited,
             send_buffer: [0u8; BLASTER_WRITE_SIZE],
             send_len: 0,
             recv_buffer: [0u8; BLASTER_READ_SIZE],
             recv_len: 0,
+            uart: UartChannel::new(()),
+            error: None,
         };
         blaster.send_buffer[0] = FTDI_MODEM_STA_DUMMY[0];
         blaster.send_buffer[1] = FTDI_MODEM_STA_DUMMY[1];
         blaster
     }
+}
+
+impl<'a, B: UsbBus, J: JtagBackend, S: EepromStorage, C: MillisClock, U: UartBridge>
+    Blaster<'a, B, J, S, C, U>
+{
+    /// Serve a custom FT245 EEPROM image to the host, e.g. one generated with [`EepromBuilder`].
+    /// Keep the strings consistent with the ones given to the `UsbDeviceBuilder`.
+    pub fn with_eeprom(mut self, eeprom: [u8; EEPROM_SIZE]) -> Self {
+        self.class.set_eeprom(eeprom);
+        self
+    }
+
+    /// Let the host write and erase the emulated EEPROM, committing images with a valid checksum to `storage`.
+    /// A valid image committed earlier is served instead of the one set before; call this after [`Blaster::with_eeprom`].
+    pub fn with_eeprom_storage<S2: EepromStorage>(
+        mut self,
+        mut storage: S2,
+    ) -> Blaster<'a, B, J, S2, C, U> {
+        let mut image = [0u8; EEPROM_SIZE];
+        if let Ok(true) = storage.load(&mut image) {
+            if checksum(&image)
+                == u16::from_le_bytes([image[CHECKSUM_OFFSET], image[CHECKSUM_OFFSET + 1]])
+            {
+                self.class.set_eeprom(image);
+            }
+        }
+        self.map_parts(|(backend, _, clock, uart)| (backend, storage, clock, uart))
+    }
+
+    /// The EEPROM image served to the host, including the last image it has written with a valid checksum.
+    /// Data after the string descriptors can hold board metadata.
+    pub fn eeprom(&self) -> &[u8; EEPROM_SIZE] {
+        self.class.eeprom()
+    }
+
+    /// Report `serial_number` in the default EEPROM image instead of "12345678", so that several blasters can be used on one host.
+    /// Pass the same string to `UsbDeviceBuilder::serial_number`, for example one made with [`SerialNumber`](crate::SerialNumber).
+    /// If you serve a custom image, set the serial number with [`EepromBuilder::serial_number`] instead.
+    pub fn with_serial_number(self, serial_number: &str) -> Self {
+        self.with_eeprom(EepromBuilder::new().serial_number(serial_number).build())
+    }
+
+    /// Hold back partially filled IN packets until the latency timer set by the host expires, the way a real FT245 does.
+    /// Without a clock, buffered data is sent on every [`Blaster::write`].
+    pub fn with_clock<C2: MillisClock>(self, clock: C2) -> Blaster<'a, B, J, S, C2, U> {
+        let last_write_ms = clock.millis();
+        let mut blaster =
+            self.map_parts(|(backend, storage, _, uart)| (backend, storage, Some(clock), uart));
+        blaster.last_write_ms = last_write_ms;
+        blaster
+    }
+
+    /// Choose when status-only packets are sent. Defaults to [`Heartbeat::Always`].
+    /// The host expects them at least every 10 milliseconds, otherwise [a BSOD could occur on Windows](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L212).
+    /// See [libftdi ftdi.c](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L2053) for more on this.
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Limit how much received data each call to [`Blaster::poll`] handles. Defaults to [`Budget::Unlimited`].
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Bridge a UART to the host over the second pair of FT245 endpoints, which are otherwise left unused.
+    /// They are described on a second interface, allocated here, so call this before allocating any other class.
+    /// The baud rate, data characteristics and flow control requested by the host are passed on to it.
+    pub fn with_uart<U2: UartBridge>(
+        mut self,
+        alloc: &'a UsbBusAllocator<B>,
+        uart: U2,
+    ) -> Blaster<'a, B, J, S, C, U2> {
+        if uart.is_attached() {
+            self.class.enable_uart(alloc);
+        }
+        self.map_parts(|(backend, storage, clock, _)| {
+            (backend, storage, clock, UartChannel::new(uart))
+        })
+    }
+
+    /// The UART bridged to the host
+    pub fn uart(&mut self) -> &mut U {
+        &mut self.uart.uart
+    }
+
+    /// The UART line settings requested by the host
+    pub fn line_coding(&self) -> &LineCoding {
+        &self.uart.line_coding
+    }
+
+    /// Move data between the host and the UART. [`Blaster::poll`] does this too.
+    /// [`BlasterError::Uart`] is returned once for each direction in which the UART dropped a byte.
+    pub fn bridge_uart(&mut self) -> Result<(), BlasterError<J::Error>> {
+        self.uart.bridge(&mut self.class)
+    }
+
+    /// The TCK frequency limit in Hz, or 0 if TCK runs as fast as the pins allow
+    pub fn tck_frequency(&self) -> u32 {
+        self.backend.tck_frequency()
+    }
+
+    /// Hold TCK high and low for at least half a period of `hz` each, in bit-bang and shift mode alike.
+    /// 0 removes the limit. With a [`Port`], this only takes effect with a delay attached.
+    /// Returns false if the backend cannot limit TCK.
+    pub fn set_tck_frequency(&mut self, hz: u32) -> bool {
+        self.backend.set_tck_frequency(hz)
+    }
+
+    /// Assert or release nTRST, returning false if it is not connected. The JTAG state is reset while it is asserted.
+    pub fn set_ntrst(&mut self, asserted: bool) -> Result<bool, BlasterError<J::Error>> {
+        self.backend.set_ntrst(asserted).map_err(BlasterError::Pin)
+    }
+
+    /// Assert or release nSRST, returning false if it is not connected
+    pub fn set_nsrst(&mut self, asserted: bool) -> Result<bool, BlasterError<J::Error>> {
+        self.backend.set_nsrst(asserted).map_err(BlasterError::Pin)
+    }
+
+    /// Pulse nTRST low, returning false if it is not connected
+    pub fn pulse_ntrst(&mut self) -> Result<bool, BlasterError<J::Error>> {
+        self.backend.pulse_ntrst().map_err(BlasterError::Pin)
+    }
+
+    /// Pulse nSRST low, returning false if it is not connected
+    pub fn pulse_nsrst(&mut self) -> Result<bool, BlasterError<J::Error>> {
+        self.backend.pulse_nsrst().map_err(BlasterError::Pin)
+    }
+
+    /// The latency timer set by the host, in milliseconds.
+    pub fn latency_timer(&self) -> u8 {
+        self.class.latency_timer()
+    }
+
+    /// The backend driving the JTAG lines
+    pub fn backend(&self) -> &J {
+        &self.backend
+    }
+
+    /// The backend driving the JTAG lines, e.g. to reconfigure a custom one
+    pub fn backend_mut(&mut self) -> &mut J {
+        &mut self.backend
+    }
 
     /// Read data from the host output endpoint into the Blaster's internal read buffer.
-    pub fn read(&mut self) -> usb_device::Result<usize> {
+    pub fn read(&mut self) -> Result<usize, BlasterError<J::Error>> {
         if self.recv_len == self.recv_buffer.len() {
-            return Err(UsbError::WouldBlock);
+            return Err(UsbError::WouldBlock.into());
         }
         let amount = self.class.read(&mut self.recv_buffer[self.recv_len..])?;
         self.recv_len += amount;
@@ -70,54 +334,349 @@ impl<
     }
 
     /// Write data to the host input endpoint from the Blaster's internal write buffer.
-    /// The heartbeat parameter must be true at least once every 10 milliseconds, so that the blaster can output the modem status. See [libftdi ftdi.c](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L2053) for more on this.
-    /// Otherwise, [a BSOD could occur on Windows](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L212)
-    /// A safe default for the heartbeat seems to be true all the time. This will output the modem status whenever the host reads the device.
-    pub fn write(&mut self, heartbeat: bool) -> usb_device::Result<usize> {
-        if self.send_len == 0 && !heartbeat {
-            return Err(UsbError::WouldBlock);
-        }
-        let res = self.class.write(&self.send_buffer[..self.send_len + 2]);
-        if res.is_ok() {
-            let amount = *res.as_ref().unwrap();
-            if amount <= 2 {
-                if amount == 1 {
-                    // TODO: how to handle a half-sent STA?
-                    panic!("Cannot recover from half-sent status");
-                }
-            } else {
-                self.send_buffer
-                    .copy_within((amount)..(self.send_len + 2), 2);
-                let actual_amount = amount - 2;
-                self.send_len -= actual_amount;
+    /// Each packet starts with the modem status, and status-only packets are sent according to the [`Heartbeat`] policy.
+    ///
+    /// With a clock attached, a partially filled packet is only sent once the latency timer expires.
+    pub fn write(&mut self) -> Result<usize, BlasterError<J::Error>> {
+        let now = self.clock.as_ref().map(MillisClock::millis);
+        let due = match now {
+            Some(now) => {
+                self.send_len == self.send_buffer.len() - 2
+                    || now.wrapping_sub(self.last_write_ms) >= self.class.latency_timer() as u32
+            }
+            None => true,
+        };
+        let send = match self.heartbeat {
+            Heartbeat::Always => due || self.send_len == 0,
+            Heartbeat::LatencyTimer => due,
+            Heartbeat::OnDemand => due && self.send_len != 0,
+        };
+        if !send {
+            return Err(UsbError::WouldBlock.into());
+        }
+        let amount = self
+            .class
+            .write(&self.send_buffer[..self.send_len + 2])?;
+        if let Some(now) = now {
+            self.last_write_ms = now;
+        }
+        // The host strips the status from every packet, so the next one starts with a fresh status.
+        // Only the data that went out with this one is dropped.
+        let data_sent = amount.saturating_sub(2);
+        self.send_buffer
+            .copy_within((2 + data_sent)..(self.send_len + 2), 2);
+        self.send_len -= data_sent;
+        Ok(amount)
+    }
+
+    /// Write one EEPROM word from the host into the staged image. Once the checksum word is written, the image is
+    /// committed to storage and served if it is valid. Returns false if the write is rejected.
+    fn write_eeprom(&mut self, word: u16, value: u16) -> bool {
+        let word = word as usize;
+        if !self.storage.is_writable() || word >= EEPROM_SIZE / 2 {
+            return false;
+        }
+        self.class.write_eeprom_word(word, value);
+        if word * 2 != CHECKSUM_OFFSET {
+            return true;
+        }
+        let image = self.class.staged_eeprom();
+        if checksum(image) != value {
+            return false;
+        }
+        if self.storage.store(image).is_err() {
+            self.store_error(BlasterError::Storage);
+            return false;
+        }
+        self.class.commit_eeprom();
+        true
+    }
+
+    /// Drive the reset lines as asked by [`BLASTER_VEN_REQ_RESET`], returning false if the request is rejected
+    fn drive_reset_lines(&mut self, lines: u16, action: u16) -> bool {
+        const RESET_RELEASE: u16 = 0;
+        const RESET_ASSERT: u16 = 1;
+        const RESET_PULSE: u16 = 2;
+
+        if lines == 0 || lines & !(RESET_NTRST | RESET_NSRST) != 0 || action > RESET_PULSE {
+            self.store_error(BlasterError::InvalidRequest(BLASTER_VEN_REQ_RESET));
+            return false;
+        }
+        let mut res = Ok(true);
+        if lines & RESET_NTRST != 0 {
+            res = match action {
+                RESET_RELEASE => self.backend.set_ntrst(false),
+                RESET_ASSERT => self.backend.set_ntrst(true),
+                _ => self.backend.pulse_ntrst(),
+            };
+        }
+        if lines & RESET_NSRST != 0 && matches!(res, Ok(true)) {
+            res = match action {
+                RESET_RELEASE => self.backend.set_nsrst(false),
+                RESET_ASSERT => self.backend.set_nsrst(true),
+                _ => self.backend.pulse_nsrst(),
+            };
+        }
+        match res {
+            Ok(true) => true,
+            Ok(false) => {
+                self.store_error(BlasterError::InvalidRequest(BLASTER_VEN_REQ_RESET));
+                false
+            }
+            Err(err) => {
+                self.store_error(BlasterError::Pin(err));
+                false
             }
         }
-        res
+    }
+
+    /// Erase the EEPROM from the host. Returns false if the erase is rejected.
+    fn erase_eeprom(&mut self) -> bool {
+        if !self.storage.is_writable() {
+            return false;
+        }
+        if self.storage.erase().is_err() {
+            self.store_error(BlasterError::Storage);
+            return false;
+        }
+        self.class.erase_eeprom();
+        true
     }
 
     /// Runs all pending operations from the internal read buffer until either no operations are left or the internal write buffer is full.
     /// If a GPIO error occurs, the JTAG state machine will enter an undefined state requiring a forced USB bus reset.
-    pub fn handle(&mut self) -> Result<(), E> {
-        self.port.handle(
+    pub fn handle(&mut self) -> Result<(), BlasterError<J::Error>> {
+        self.handle_bytes(usize::MAX).map(|_| ())
+    }
+
+    /// Handle at most `budget` received bytes, returning how many were handled
+    fn handle_bytes(&mut self, budget: usize) -> Result<usize, BlasterError<J::Error>> {
+        self.decoder.handle(
+            &mut self.backend,
             &mut self.recv_buffer,
             &mut self.recv_len,
             &mut self.send_buffer[2..],
             &mut self.send_len,
+            budget,
         )
     }
+
+    /// Poll the USB device, read data from the host, handle it within the [`Budget`] and write the replies.
+    /// This does everything [`Blaster::read`], [`Blaster::handle`] and [`Blaster::write`] do, in the right order.
+    /// Use those instead if the USB device has other classes to poll.
+    pub fn poll(
+        &mut self,
+        usb_dev: &mut UsbDevice<'a, B>,
+    ) -> Result<PollSummary, BlasterError<J::Error>> {
+        usb_dev.poll(&mut [self]);
+        let mut summary = PollSummary {
+            received: would_block(self.read())?,
+            ..PollSummary::default()
+        };
+        let start = self.clock.as_ref().map(MillisClock::millis);
+        summary.handled = match (self.budget, start) {
+            (Budget::Unlimited, _) | (Budget::Millis(_), None) => self.handle_bytes(usize::MAX)?,
+            (Budget::Bytes(bytes), _) => self.handle_bytes(bytes)?,
+            (Budget::Millis(ms), Some(start)) => {
+                let mut handled = 0;
+                loop {
+                    let amount = self.handle_bytes(MILLIS_BUDGET_CHUNK)?;
+                    handled += amount;
+                    let now = self.clock.as_ref().map_or(start, MillisClock::millis);
+                    if amount == 0 || now.wrapping_sub(start) >= ms {
+                        break handled;
+                    }
+                }
+            }
+        };
+        summary.sent = would_block(self.write())?;
+        summary.pending = self.recv_len;
+        self.bridge_uart()?;
+        Ok(summary)
+    }
+
+    /// Take the first error raised while handling a USB reset or control request, since those cannot be returned to the caller of `UsbDevice::poll`.
+    pub fn take_error(&mut self) -> Option<BlasterError<J::Error>> {
+        self.error.take()
+    }
+
+    /// Return the JTAG side to its power-on state, leaving the UART alone
+    fn reset_jtag(&mut self) {
+        self.class.reset();
+        self.decoder.reset();
+        if let Err(err) = self.backend.reset() {
+            self.store_error(BlasterError::Pin(err));
+        }
+        self.send_len = 0;
+        self.recv_len = 0;
+    }
+
+    /// Keep the first error until the application takes it
+    fn store_error(&mut self, error: BlasterError<J::Error>) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    /// Swap the backend for one built from it, keeping everything else
+    fn map_backend<J2: JtagBackend<Error = J::Error>>(
+        self,
+        f: impl FnOnce(J) -> J2,
+    ) -> Blaster<'a, B, J2, S, C, U> {
+        self.map_parts(|(backend, storage, clock, uart)| (f(backend), storage, clock, uart))
+    }
+
+    /// Replace the backend, storage, clock and UART, keeping the buffers and the state
+    fn map_parts<
+        J2: JtagBackend<Error = J::Error>,
+        S2: EepromStorage,
+        C2: MillisClock,
+        U2: UartBridge,
+    >(
+        self,
+        f: impl FnOnce((J, S, Option<C>, UartChannel<U>)) -> (J2, S2, Option<C2>, UartChannel<U2>),
+    ) -> Blaster<'a, B, J2, S2, C2, U2> {
+        let (backend, storage, clock, uart) =
+            f((self.backend, self.storage, self.clock, self.uart));
+        Blaster {
+            class: self.class,
+            backend,
+            decoder: self.decoder,
+            storage,
+            clock,
+            last_write_ms: self.last_write_ms,
+            heartbeat: self.heartbeat,
+            budget: self.budget,
+            send_buffer: self.send_buffer,
+            send_len: self.send_len,
+            recv_buffer: self.recv_buffer,
+            recv_len: self.recv_len,
+            uart,
+            error: self.error,
+        }
+    }
 }
 
+// Each builder changes one of the many optional parts of the port, so the full type has to be spelled out
+#[allow(clippy::type_complexity)]
 impl<
-        B,
+        'a,
+        B: UsbBus,
         E,
-        TDI: OutputPin<Error = E>,
-        TCK: OutputPin<Error = E>,
-        TMS: OutputPin<Error = E>,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
         TDO: InputPin<Error = E>,
-    > UsbClass<B> for Blaster<'_, B, E, TDI, TCK, TMS, TDO>
+        AS: ActiveSerialPins<E>,
+        PS: PassiveSerialPins<E>,
+        D: TckDelay,
+        RT: ReturnClock<E>,
+        RS: ResetPins<E>,
+        W: MaskedWrite<E>,
+        SP: ShiftSpi<E>,
+        S: EepromStorage,
+        C: MillisClock,
+        U: UartBridge,
+    > Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT, RS, W, SP>, S, C, U>
+{
+    /// Attach the Active Serial (AS) pins, so that Quartus can program a serial configuration device (EPCS/EPCQ) directly.
+    /// nCE and nCS follow the bits the host sends and DATAOUT is reported back in bit-bang reads.
+    pub fn with_active_serial<AS2: ActiveSerialPins<E>>(
+        self,
+        as_pins: AS2,
+    ) -> Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO, AS2, PS, D, RT, RS, W, SP>, S, C, U> {
+        self.map_backend(|port| port.with_active_serial(as_pins))
+    }
+
+    /// Attach the Passive Serial (PS) status pins and switch to PS mode, so that Quartus can configure an FPGA strapped for passive serial.
+    /// The TCK, TDI and TMS pins then drive DCLK, DATA0 and nCONFIG, and CONF_DONE and nSTATUS are reported back in place of TDO and DATAOUT.
+    pub fn with_passive_serial<PS2: PassiveSerialPins<E>>(
+        self,
+        ps_pins: PS2,
+    ) -> Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO, AS, PS2, D, RT, RS, W, SP>, S, C, U> {
+        self.map_backend(|port| port.with_passive_serial(ps_pins))
+    }
+
+    /// Mark the lines that pass through inverting buffers. The JTAG state is tracked on the logic levels.
+    pub fn with_polarity(mut self, polarity: Polarity) -> Self {
+        self.backend.set_polarity(polarity);
+        self
+    }
+
+    /// Attach a delay, so that TCK stays under the frequency set with [`Blaster::set_tck_frequency`] or by the host.
+    pub fn with_delay<D2: TckDelay>(
+        self,
+        delay: D2,
+    ) -> Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO, AS, PS, D2, RT, RS, W, SP>, S, C, U> {
+        self.map_backend(|port| port.with_delay(delay))
+    }
+
+    /// Attach a return clock (RTCK), for ARM cores in the chain that need adaptive clocking.
+    /// Each TCK edge in bit-bang and shift mode then waits for RTCK to follow, and [`BlasterError::ReturnClockTimeout`] is returned if it does not.
+    pub fn with_return_clock<RT2: ReturnClock<E>>(
+        self,
+        rtck: RT2,
+    ) -> Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT2, RS, W, SP>, S, C, U> {
+        self.map_backend(|port| port.with_return_clock(rtck))
+    }
+
+    /// Give up on RTCK after sampling it `samples` times without it following TCK. Defaults to 10000.
+    pub fn with_rtck_timeout(mut self, samples: u32) -> Self {
+        self.backend.set_rtck_timeout(samples);
+        self
+    }
+
+    /// Attach the nTRST and nSRST lines, so that the TAPs and the target system can be reset from firmware or with [`BLASTER_VEN_REQ_RESET`].
+    /// Both lines are released on a USB reset.
+    pub fn with_reset_pins<RS2: ResetPins<E>>(
+        self,
+        reset_pins: RS2,
+    ) -> Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT, RS2, W, SP>, S, C, U> {
+        self.map_backend(|port| port.with_reset_pins(reset_pins))
+    }
+
+    /// Write TDI, TCK and TMS with a single masked register write instead of three pin writes, for boards where they are on one GPIO port.
+    /// The pins passed to [`Blaster::new`] are still used to float the lines and to park them on a USB reset.
+    pub fn with_masked_write<W2: MaskedWrite<E>>(
+        self,
+        masked: W2,
+    ) -> Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT, RS, W2, SP>, S, C, U> {
+        self.map_backend(|port| port.with_masked_write(masked))
+    }
+
+    /// Shift runs of shift-mode bytes on an SPI peripheral, for bitstream uploads at hardware SPI speed.
+    /// TDI and TCK are handed to it for shift-mode bytes and taken back for bit-bang bytes.
+    /// Bytes are still bit-banged in PS mode, while a configuration device is selected, when TDI, TCK or TDO are inverted,
+    /// while the outputs are disabled and with a return clock attached.
+    pub fn with_spi<SP2: ShiftSpi<E>>(
+        self,
+        spi: SP2,
+    ) -> Blaster<'a, B, Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT, RS, W, SP2>, S, C, U> {
+        self.map_backend(|port| port.with_spi(spi))
+    }
+
+    /// How long nTRST and nSRST are held low when pulsed, in microseconds. Both default to 1000.
+    /// The pulses are timed with the delay attached with [`Blaster::with_delay`], and are as short as the pins allow without one.
+    pub fn with_reset_pulse(mut self, ntrst_us: u32, nsrst_us: u32) -> Self {
+        self.backend.set_reset_pulse(ntrst_us, nsrst_us);
+        self
+    }
+
+    /// The configuration scheme the blaster lines are currently used for.
+    pub fn mode(&self) -> Mode {
+        self.backend.mode()
+    }
+
+    /// Switch between JTAG and Passive Serial (PS) mode, for boards that route both.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.backend.set_mode(mode);
+    }
+}
+
+impl<B, J: JtagBackend, S: EepromStorage, C: MillisClock, U: UartBridge> UsbClass<B>
+    for Blaster<'_, B, J, S, C, U>
 where
     B: UsbBus,
-    E: core::fmt::Debug,
 {
     fn get_configuration_descriptors(
         &self,
@@ -127,77 +686,158 @@ where
     }
 
     fn reset(&mut self) {
-        self.class.reset();
-        // TODO: if this fails, there are bigger, device-level problems.
-        self.port.reset().expect("unable to reset port");
-        self.send_len = 0;
-        self.recv_len = 0;
+        self.reset_jtag();
+        self.uart.purge_rx();
+        self.uart.purge_tx();
     }
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
-        self.class.control_in(xfer);
+        let req = *xfer.request();
+        let res = if req.request_type == RequestType::Vendor
+            && req.request == BLASTER_VEN_REQ_GET_TCK_FREQ
+        {
+            xfer.accept_with(&self.backend.tck_frequency().to_le_bytes())
+        } else {
+            self.class.control_in(xfer)
+        };
+        if let Err(err) = res {
+            self.store_error(err.into());
+        }
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
         /// See [Linux kernel ftdi_sio.h](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L74)
         const FTDI_VEN_REQ_RESET: u8 = 0x00;
         /// [Set chip baud rate](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L104)
-        const _FTDI_VEN_REQ_SET_BAUDRATE: u8 = 0x01;
+        const FTDI_VEN_REQ_SET_BAUDRATE: u8 = 0x03;
         /// [Set RS232 line characteristics](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L198)
-        const _FTDI_VEN_REQ_SET_DATA_CHAR: u8 = 0x02;
+        const FTDI_VEN_REQ_SET_DATA_CHAR: u8 = 0x04;
         /// [Set chip flow control](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L277)
-        const _FTDI_VEN_REQ_SET_FLOW_CTRL: u8 = 0x03;
+        const FTDI_VEN_REQ_SET_FLOW_CTRL: u8 = 0x02;
         /// [Set modem ctrl](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L232)
-        const _FTDI_VEN_REQ_SET_MODEM_CTRL: u8 = 0x04;
+        const _FTDI_VEN_REQ_SET_MODEM_CTRL: u8 = 0x01;
         /// [Set special event character](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L365)
         const _FTDI_VEN_REQ_SET_EVENT_CHAR: u8 = 0x06;
         /// [Set parity error replacement character](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L382)
         const _FTDI_VEN_REQ_SET_ERR_CHAR: u8 = 0x07;
         /// [Set latency timer](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L324)
-        const _FTDI_VEN_REQ_SET_LAT_TIMER: u8 = 0x09;
+        const FTDI_VEN_REQ_SET_LAT_TIMER: u8 = 0x09;
         /// [Set bitmode](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L1921)
         const _FTDI_VEN_REQ_SET_BITMODE: u8 = 0x0B;
         /// See [libftdi ftdi.h](https://github.com/lipro/libftdi/blob/master/src/ftdi.h#L169)
-        /// This request is rejected -- EEPROM is read-only.
+        /// This request is rejected unless the EEPROM storage is writable.
         const FTDI_VEN_REQ_WR_EEPROM: u8 = 0x91;
-        /// This request is rejected -- EEPROM is read-only.
+        /// This request is rejected unless the EEPROM storage is writable.
         const FTDI_VEN_REQ_ES_EEPROM: u8 = 0x92;
 
-        let req = xfer.request();
-        if req.request_type == RequestType::Vendor {
-            match req.request {
-                FTDI_VEN_REQ_RESET => {
-                    const RESET_SIO: u16 = 0x0000;
-                    const RESET_PURGE_RX: u16 = 0x0001;
-                    const RESET_PURGE_TX: u16 = 0x0002;
-                    match req.value {
-                        RESET_SIO => {
-                            self.reset();
-                            xfer.accept().unwrap();
-                        }
-                        RESET_PURGE_RX => {
-                            self.recv_len = 0;
-                            xfer.accept().unwrap();
-                        }
-                        RESET_PURGE_TX => {
-                            self.send_len = 0;
-                            xfer.accept().unwrap();
-                        }
-                        _ => {
-                            xfer.reject().unwrap();
-                        }
+        let req = *xfer.request();
+        if req.request_type != RequestType::Vendor {
+            return;
+        }
+        // The FTDI requests address a channel with its interface number in the lower byte of the index
+        let channel = self.class.channel(req.index);
+        let accepted = match req.request {
+            FTDI_VEN_REQ_RESET => {
+                const RESET_SIO: u16 = 0x0000;
+                const RESET_PURGE_RX: u16 = 0x0001;
+                const RESET_PURGE_TX: u16 = 0x0002;
+                match (channel, req.value) {
+                    (Some(Channel::Jtag), RESET_SIO) => {
+                        self.reset_jtag();
+                        true
+                    }
+                    (Some(Channel::Jtag), RESET_PURGE_RX) => {
+                        self.recv_len = 0;
+                        true
+                    }
+                    (Some(Channel::Jtag), RESET_PURGE_TX) => {
+                        self.send_len = 0;
+                        true
+                    }
+                    (Some(Channel::Uart), RESET_SIO) => {
+                        self.uart.purge_rx();
+                        self.uart.purge_tx();
+                        true
+                    }
+                    (Some(Channel::Uart), RESET_PURGE_RX) => {
+                        self.uart.purge_rx();
+                        true
+                    }
+                    (Some(Channel::Uart), RESET_PURGE_TX) => {
+                        self.uart.purge_tx();
+                        true
+                    }
+                    _ => {
+                        self.store_error(BlasterError::InvalidRequest(req.request));
+                        false
                     }
                 }
-                FTDI_VEN_REQ_WR_EEPROM => {
-                    xfer.reject().unwrap();
-                }
-                FTDI_VEN_REQ_ES_EEPROM => {
-                    xfer.reject().unwrap();
+            }
+            FTDI_VEN_REQ_SET_LAT_TIMER => {
+                // Must be a value between 1 and 255
+                match (channel, req.value) {
+                    (Some(Channel::Jtag), 1..=255) => {
+                        self.class.set_latency_timer(req.value as u8);
+                        true
+                    }
+                    // UART packets are never held back
+                    (Some(Channel::Uart), 1..=255) => true,
+                    _ => {
+                        self.store_error(BlasterError::InvalidRequest(req.request));
+                        false
+                    }
                 }
-                _ => {
-                    xfer.accept().unwrap();
+            }
+            // The line settings only apply to the UART
+            FTDI_VEN_REQ_SET_BAUDRATE if channel == Some(Channel::Uart) => {
+                let mut coding = self.uart.line_coding;
+                coding.set_ftdi_baud_rate(req.value, req.index >> 8);
+                self.uart.set_line_coding(coding)
+            }
+            FTDI_VEN_REQ_SET_DATA_CHAR if channel == Some(Channel::Uart) => {
+                let mut coding = self.uart.line_coding;
+                coding.set_ftdi_data(req.value) && self.uart.set_line_coding(coding)
+            }
+            FTDI_VEN_REQ_SET_FLOW_CTRL if channel == Some(Channel::Uart) => {
+                let mut coding = self.uart.line_coding;
+                coding.set_ftdi_flow_control(req.index);
+                self.uart.set_line_coding(coding)
+            }
+            FTDI_VEN_REQ_SET_BAUDRATE | FTDI_VEN_REQ_SET_DATA_CHAR | FTDI_VEN_REQ_SET_FLOW_CTRL => {
+                false
+            }
+            BLASTER_VEN_REQ_SET_TCK_FREQ => {
+                let accepted = self
+                    .backend
+                    .set_tck_frequency(req.value as u32 | (req.index as u32) << 16);
+                if !accepted {
+                    self.store_error(BlasterError::InvalidRequest(req.request));
                 }
+                accepted
             }
+            BLASTER_VEN_REQ_RESET => self.drive_reset_lines(req.value, req.index),
+            // [Write EEPROM location](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L4165): the word is in the value, its address in the index
+            FTDI_VEN_REQ_WR_EEPROM => self.write_eeprom(req.index, req.value),
+            FTDI_VEN_REQ_ES_EEPROM => self.erase_eeprom(),
+            _ => true,
+        };
+        let res = if accepted {
+            xfer.accept()
+        } else {
+            xfer.reject()
+        };
+        if let Err(err) = res {
+            self.store_error(err.into());
         }
     }
 }
+
+/// Treat an endpoint that is not ready as having transferred nothing
+pub(crate) fn would_block<E>(
+    res: Result<usize, BlasterError<E>>,
+) -> Result<usize, BlasterError<E>> {
+    match res {
+        Err(BlasterError::Usb(UsbError::WouldBlock)) => Ok(0),
+        res => res,
+    }
+}
@@ -1,6 +1,6 @@
 use usb_device::{class_prelude::*, control::RequestType, Result, UsbDirection};
 
-use super::ft245::ROM;
+use super::ft245::{EEPROM_SIZE, ROM};
 
 /// See [ftdi.h](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L2049)
 const DATA_READY: u8 = 0b0000_0001;
@@ -8,58 +8,46 @@ const RECEIVE_LINE_SIGNAL_DETECT_ACTIVE: u8 = 0b1000_0000;
 const RING_INDICATOR_ACTIVE: u8 = 0b0100_0000;
 pub const FTDI_MODEM_STA_DUMMY: [u8; 2] = [DATA_READY, RECEIVE_LINE_SIGNAL_DETECT_ACTIVE | RING_INDICATOR_ACTIVE];
 
+/// The side of the blaster a vendor request is addressed to
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Channel {
+    Jtag,
+    Uart,
+}
+
 pub struct BlasterClass<'a, B: UsbBus> {
     iface: InterfaceNumber,
     pub read_ep: EndpointOut<'a, B>,
     pub write_ep: EndpointIn<'a, B>,
-    _fake_write_ep: EndpointIn<'a, B>,
-    _fake_read_ep: EndpointOut<'a, B>,
+    uart_write_ep: EndpointIn<'a, B>,
+    uart_read_ep: EndpointOut<'a, B>,
+    /// The interface of the UART endpoints, once they are described to the host
+    uart_iface: Option<InterfaceNumber>,
+    /// The image served to the host
+    eeprom: [u8; EEPROM_SIZE],
+    /// The image being written by the host, served once its checksum is committed
+    staged_eeprom: [u8; EEPROM_SIZE],
+    latency_timer: u8,
 }
 
+/// Must be a value between 1 and 255
+/// [16 is the default](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L310)
+pub const FTDI_LAT_TIMER_DEFAULT: u8 = 16;
+
 impl<'a, B: UsbBus> UsbClass<B> for BlasterClass<'a, B> {
     fn get_configuration_descriptors(&self, w: &mut DescriptorWriter) -> Result<()> {
         w.interface(self.iface, 0xFF, 0xFF, 0xFF)?;
         w.endpoint(&self.write_ep)?;
-        w.endpoint(&self.read_ep)
+        w.endpoint(&self.read_ep)?;
+        if let Some(uart_iface) = self.uart_iface {
+            w.interface(uart_iface, 0xFF, 0xFF, 0xFF)?;
+            w.endpoint(&self.uart_read_ep)?;
+            w.endpoint(&self.uart_write_ep)?;
+        }
+        Ok(())
     }
 
     fn reset(&mut self) {}
-
-    fn control_in(&mut self, xfer: ControlIn<B>) {
-        /// [Get modem status](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L2049)
-        const FTDI_VEN_REQ_GET_MODEM_STA: u8 = 0x05;
-        /// [Get latency timer](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L302)
-        const FTDI_VEN_REQ_GET_LAT_TIMER: u8 = 0x0A;
-        /// [Read pins](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L1972)
-        const _FTDI_VEN_REQ_RD_PINS: u8 = 0x0C;
-        //// [Read EEPROM location](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L4025)
-        const FTDI_VEN_REQ_RD_EEPROM: u8 = 0x90;
-
-        /// Must be a value between 1 and 255
-        /// [16 is the default](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L310)
-        const FTDI_LAT_TIMER_DUMMY: [u8; 1] = ['6' as u8];
-
-        let req = xfer.request();
-        if req.request_type == RequestType::Vendor {
-            match req.request {
-                FTDI_VEN_REQ_RD_EEPROM => {
-                    let addr = (((req.value >> 8) & 0x3f) << 1) as usize;
-                    xfer.accept_with(&ROM[addr..=addr + 1]).unwrap();
-                }
-                FTDI_VEN_REQ_GET_MODEM_STA => {
-                    xfer.accept_with_static(&FTDI_MODEM_STA_DUMMY)
-                        .unwrap();
-                }
-                FTDI_VEN_REQ_GET_LAT_TIMER => {
-                    xfer.accept_with_static(&FTDI_LAT_TIMER_DUMMY)
-                        .unwrap();
-                }
-                _ => {
-                    xfer.accept_with_static(&[0u8; 2]).unwrap();
-                }
-            }
-        }
-    }
 }
 
 impl<B: UsbBus> BlasterClass<'_, B> {
@@ -71,7 +59,7 @@ impl<B: UsbBus> BlasterClass<'_, B> {
         BlasterClass {
             iface: alloc.interface(),
             /// See INTERFACE_A: https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L178
-            _fake_read_ep: alloc
+            uart_read_ep: alloc
                 .alloc(
                     Some(EndpointAddress::from_parts(0x01, UsbDirection::Out)),
                     EndpointType::Bulk,
@@ -95,7 +83,7 @@ impl<B: UsbBus> BlasterClass<'_, B> {
                     1,
                 )
                 .expect("alloc_ep failed"),
-            _fake_write_ep: alloc
+            uart_write_ep: alloc
                 .alloc(
                     Some(EndpointAddress::from_parts(0x02, UsbDirection::In)),
                     EndpointType::Bulk,
@@ -103,6 +91,107 @@ impl<B: UsbBus> BlasterClass<'_, B> {
                     1,
                 )
                 .expect("alloc_ep failed"),
+            uart_iface: None,
+            eeprom: ROM,
+            staged_eeprom: ROM,
+            latency_timer: FTDI_LAT_TIMER_DEFAULT,
+        }
+    }
+
+    /// Answer the vendor requests that read the FT245 state
+    pub fn control_in(&mut self, xfer: ControlIn<B>) -> Result<()> {
+        /// [Get modem status](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L2049)
+        const FTDI_VEN_REQ_GET_MODEM_STA: u8 = 0x05;
+        /// [Get latency timer](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L302)
+        const FTDI_VEN_REQ_GET_LAT_TIMER: u8 = 0x0A;
+        /// [Read pins](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L1972)
+        const _FTDI_VEN_REQ_RD_PINS: u8 = 0x0C;
+        //// [Read EEPROM location](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L4025)
+        const FTDI_VEN_REQ_RD_EEPROM: u8 = 0x90;
+
+        let req = xfer.request();
+        if req.request_type == RequestType::Vendor {
+            match req.request {
+                FTDI_VEN_REQ_RD_EEPROM => {
+                    // libftdi and the Linux driver pass the word address in the index, Quartus in the upper byte of the value
+                    let word = if req.index != 0 {
+                        req.index
+                    } else {
+                        req.value >> 8
+                    };
+                    let addr = ((word & 0x3f) << 1) as usize;
+                    xfer.accept_with(&self.eeprom[addr..=addr + 1])
+                }
+                FTDI_VEN_REQ_GET_MODEM_STA => {
+                    xfer.accept_with_static(&FTDI_MODEM_STA_DUMMY)
+                }
+                FTDI_VEN_REQ_GET_LAT_TIMER => {
+                    xfer.accept_with(&[self.latency_timer])
+                }
+                _ => {
+                    xfer.accept_with_static(&[0u8; 2])
+                }
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn eeprom(&self) -> &[u8; EEPROM_SIZE] {
+        &self.eeprom
+    }
+
+    pub fn set_eeprom(&mut self, eeprom: [u8; EEPROM_SIZE]) {
+        self.eeprom = eeprom;
+        self.staged_eeprom = eeprom;
+    }
+
+    /// Write one little-endian word, as a host writing the EEPROM does
+    pub fn write_eeprom_word(&mut self, word: usize, value: u16) {
+        self.staged_eeprom[2 * word] = value as u8;
+        self.staged_eeprom[2 * word + 1] = (value >> 8) as u8;
+    }
+
+    /// The image with the words written by the host so far
+    pub fn staged_eeprom(&self) -> &[u8; EEPROM_SIZE] {
+        &self.staged_eeprom
+    }
+
+    /// Serve the image written by the host
+    pub fn commit_eeprom(&mut self) {
+        self.eeprom = self.staged_eeprom;
+    }
+
+    /// Leave the EEPROM blank, as an erased 93C46 reads back all ones
+    pub fn erase_eeprom(&mut self) {
+        self.set_eeprom([0xFF; EEPROM_SIZE]);
+    }
+
+    /// How long a partially filled IN packet may be held back, in milliseconds
+    pub fn latency_timer(&self) -> u8 {
+        self.latency_timer
+    }
+
+    pub fn set_latency_timer(&mut self, latency_timer: u8) {
+        self.latency_timer = latency_timer;
+    }
+
+    /// The channel addressed by the interface number in the low byte of the index of a vendor request
+    pub fn channel(&self, index: u16) -> Option<Channel> {
+        let iface = index as u8;
+        if iface == u8::from(self.iface) {
+            Some(Channel::Jtag)
+        } else if self.uart_iface.map(u8::from) == Some(iface) {
+            Some(Channel::Uart)
+        } else {
+            None
+        }
+    }
+
+    /// Describe the UART endpoints to the host, on an interface of their own
+    pub fn enable_uart(&mut self, alloc: &UsbBusAllocator<B>) {
+        if self.uart_iface.is_none() {
+            self.uart_iface = Some(alloc.interface());
         }
     }
 
@@ -113,4 +202,12 @@ impl<B: UsbBus> BlasterClass<'_, B> {
     pub fn write(&mut self, data: &[u8]) -> Result<usize> {
         self.write_ep.write(data)
     }
+
+    pub fn read_uart(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.uart_read_ep.read(data)
+    }
+
+    pub fn write_uart(&mut self, data: &[u8]) -> Result<usize> {
+        self.uart_write_ep.write(data)
+    }
 }
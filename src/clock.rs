@@ -0,0 +1,10 @@
+/// A free-running millisecond counter, such as one driven by SysTick. It is allowed to wrap around.
+pub trait MillisClock {
+    fn millis(&self) -> u32;
+}
+
+impl MillisClock for fn() -> u32 {
+    fn millis(&self) -> u32 {
+        self()
+    }
+}
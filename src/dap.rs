@@ -0,0 +1,1027 @@
+use hal::digital::v2::{InputPin, OutputPin};
+use usb_device::descriptor::descriptor_type;
+use usb_device::endpoint::{Endpoint, EndpointDirection};
+use usb_device::{class_prelude::*, device::UsbDevice};
+
+use crate::blaster::{would_block, PollSummary};
+use crate::delay::TckDelay;
+use crate::error::BlasterError;
+use crate::pins::{Driven, Polarity, ResetPins, TristatePin};
+use crate::port::Port;
+
+/// Size of a command or response packet, which is the bulk endpoint size at full speed
+const DAP_PACKET_SIZE: usize = 64;
+/// TCK frequency until the host sets one, as in the reference firmware
+const DAP_DEFAULT_CLOCK: u32 = 1_000_000;
+/// Most devices in the JTAG chain that can be configured
+const DAP_MAX_DEVICES: usize = 8;
+/// Hosts find a v2 probe by looking for this in its interface string
+const DAP_INTERFACE_STRING: &str = "CMSIS-DAP";
+/// The alternate setting that carries [`DAP_INTERFACE_STRING`]
+const DAP_ALT_SETTING_NAMED: u8 = 1;
+
+// [Commands](https://arm-software.github.io/CMSIS_5/DAP/html/group__DAP__Commands__gr.html)
+const ID_DAP_INFO: u8 = 0x00;
+const ID_DAP_HOST_STATUS: u8 = 0x01;
+const ID_DAP_CONNECT: u8 = 0x02;
+const ID_DAP_DISCONNECT: u8 = 0x03;
+const ID_DAP_TRANSFER_CONFIGURE: u8 = 0x04;
+const ID_DAP_TRANSFER: u8 = 0x05;
+const ID_DAP_TRANSFER_BLOCK: u8 = 0x06;
+const ID_DAP_TRANSFER_ABORT: u8 = 0x07;
+const ID_DAP_WRITE_ABORT: u8 = 0x08;
+const ID_DAP_DELAY: u8 = 0x09;
+const ID_DAP_RESET_TARGET: u8 = 0x0A;
+const ID_DAP_SWJ_PINS: u8 = 0x10;
+const ID_DAP_SWJ_CLOCK: u8 = 0x11;
+const ID_DAP_SWJ_SEQUENCE: u8 = 0x12;
+const ID_DAP_JTAG_SEQUENCE: u8 = 0x14;
+const ID_DAP_JTAG_CONFIGURE: u8 = 0x15;
+const ID_DAP_JTAG_IDCODE: u8 = 0x16;
+/// Sent back for commands that are not supported
+const ID_DAP_INVALID: u8 = 0xFF;
+
+const DAP_OK: u8 = 0x00;
+const DAP_ERROR: u8 = 0xFF;
+
+// DAP_Info identifiers
+const DAP_ID_VENDOR: u8 = 0x01;
+const DAP_ID_PRODUCT: u8 = 0x02;
+const DAP_ID_SER_NUM: u8 = 0x03;
+const DAP_ID_CMSIS_DAP_VER: u8 = 0x04;
+const DAP_ID_FW_VER: u8 = 0x09;
+const DAP_ID_CAPABILITIES: u8 = 0xF0;
+const DAP_ID_PACKET_COUNT: u8 = 0xFE;
+const DAP_ID_PACKET_SIZE: u8 = 0xFF;
+
+/// Only JTAG is supported, not SWD
+const DAP_CAP_JTAG: u8 = 0x02;
+const DAP_PORT_DEFAULT: u8 = 0;
+const DAP_PORT_JTAG: u8 = 2;
+
+// Transfer request bits
+const DAP_TRANSFER_APNDP: u8 = 0x01;
+const DAP_TRANSFER_RNW: u8 = 0x02;
+const DAP_TRANSFER_MATCH_VALUE: u8 = 0x10;
+const DAP_TRANSFER_MATCH_MASK: u8 = 0x20;
+
+// Transfer responses
+const DAP_TRANSFER_OK: u8 = 0x01;
+const DAP_TRANSFER_WAIT: u8 = 0x02;
+/// No valid acknowledge from the target
+const DAP_TRANSFER_NO_ACK: u8 = 0x07;
+const DAP_TRANSFER_ERROR: u8 = 0x08;
+const DAP_TRANSFER_MISMATCH: u8 = 0x10;
+
+/// Address of the DP RDBUFF register, which returns the result of the last posted read
+const DP_RDBUFF: u8 = 0x0C;
+
+// [JTAG-DP instructions](https://developer.arm.com/documentation/ihi0031/latest)
+const JTAG_ABORT: u32 = 0x08;
+const JTAG_DPACC: u32 = 0x0A;
+const JTAG_APACC: u32 = 0x0B;
+const JTAG_IDCODE: u32 = 0x0E;
+
+// DAP_SWJ_Pins bits
+const DAP_SWJ_TCK: u8 = 0x01;
+const DAP_SWJ_TMS: u8 = 0x02;
+const DAP_SWJ_TDI: u8 = 0x04;
+const DAP_SWJ_TDO: u8 = 0x08;
+/// Active-low reset lines, which read back high while released or not connected
+const DAP_SWJ_NTRST: u8 = 0x20;
+const DAP_SWJ_NRESET: u8 = 0x80;
+/// The lines that read back as last driven
+const DAP_SWJ_DRIVEN: u8 = DAP_SWJ_TCK | DAP_SWJ_TMS | DAP_SWJ_TDI | DAP_SWJ_NTRST | DAP_SWJ_NRESET;
+/// Longest wait for the pins to settle that DAP_SWJ_Pins accepts, in microseconds
+const DAP_SWJ_MAX_WAIT_US: u32 = 3_000_000;
+
+/// A CMSIS-DAP v2 probe on a pair of bulk endpoints, for probe-rs and pyOCD to debug Cortex-M parts through a JTAG Debug Port (JTAG-DP).
+/// It covers the DAP_Info, DAP_JTAG_Sequence, DAP_Transfer and DAP_SWJ_Clock commands and what is needed around them to connect; SWD is not supported.
+/// Its interface has a second alternate setting with the "CMSIS-DAP" string that hosts look for to recognize it.
+pub struct CmsisDap<
+    'a,
+    B: UsbBus,
+    E,
+    TDI: TristatePin<Error = E>,
+    TCK: TristatePin<Error = E>,
+    TMS: TristatePin<Error = E>,
+    TDO: InputPin<Error = E>,
+    D: TckDelay = (),
+    RS: ResetPins<E> = (),
+> {
+    iface: InterfaceNumber,
+    interface_string: StringIndex,
+    /// The alternate setting selected by the host. Both describe the same endpoints.
+    alt_setting: u8,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+    port: Port<E, TDI, TCK, TMS, TDO, (), (), D, (), RS>,
+    /// IR length of each device in the chain, starting from the one closest to TDO
+    ir_lengths: [u8; DAP_MAX_DEVICES],
+    device_count: usize,
+    idle_cycles: u8,
+    wait_retry: u16,
+    match_retry: u16,
+    match_mask: u32,
+    /// Levels last driven by DAP_SWJ_Pins. The reset lines read high while released or not connected.
+    pins: u8,
+    request: [u8; DAP_PACKET_SIZE],
+    request_len: usize,
+    response: [u8; DAP_PACKET_SIZE],
+    response_len: usize,
+    /// The first error raised while handling a USB reset
+    error: Option<BlasterError<E>>,
+}
+
+impl<
+        'a,
+        B: UsbBus,
+        E,
+        TDI: OutputPin<Error = E>,
+        TCK: OutputPin<Error = E>,
+        TMS: OutputPin<Error = E>,
+        TDO: InputPin<Error = E>,
+    > CmsisDap<'a, B, E, Driven<TDI>, Driven<TCK>, Driven<TMS>, TDO>
+{
+    /// Allocate a CMSIS-DAP probe on the USB bus. Takes control of the four JTAG pins.
+    /// TDI, TCK and TMS are always driven; use [`CmsisDap::new_tristate`] if they are shared with another device.
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        tdi: TDI,
+        tck: TCK,
+        tms: TMS,
+        tdo: TDO,
+    ) -> CmsisDap<'a, B, E, Driven<TDI>, Driven<TCK>, Driven<TMS>, TDO> {
+        CmsisDap::new_tristate(alloc, Driven(tdi), Driven(tck), Driven(tms), tdo)
+    }
+}
+
+impl<
+        'a,
+        B: UsbBus,
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+    > CmsisDap<'a, B, E, TDI, TCK, TMS, TDO>
+{
+    /// Allocate a CMSIS-DAP probe on the USB bus. Takes control of the four JTAG pins.
+    /// TDI, TCK and TMS are released to high impedance while the host is disconnected.
+    pub fn new_tristate(
+        alloc: &'a UsbBusAllocator<B>,
+        tdi: TDI,
+        tck: TCK,
+        tms: TMS,
+        tdo: TDO,
+    ) -> CmsisDap<'a, B, E, TDI, TCK, TMS, TDO> {
+        let mut ir_lengths = [0u8; DAP_MAX_DEVICES];
+        // A lone Cortex-M DAP until the host configures the chain
+        ir_lengths[0] = 4;
+        let mut port = Port::new(tdi, tck, tms, tdo);
+        port.set_tck_frequency(DAP_DEFAULT_CLOCK);
+        CmsisDap {
+            iface: alloc.interface(),
+            interface_string: alloc.string(),
+            alt_setting: 0,
+            read_ep: alloc.bulk(DAP_PACKET_SIZE as u16),
+            write_ep: alloc.bulk(DAP_PACKET_SIZE as u16),
+            port,
+            ir_lengths,
+            device_count: 1,
+            idle_cycles: 0,
+            wait_retry: 100,
+            match_retry: 0,
+            match_mask: 0,
+            pins: DAP_SWJ_NTRST | DAP_SWJ_NRESET,
+            request: [0u8; DAP_PACKET_SIZE],
+            request_len: 0,
+            response: [0u8; DAP_PACKET_SIZE],
+            response_len: 0,
+            error: None,
+        }
+    }
+}
+
+impl<
+        'a,
+        B: UsbBus,
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+        D: TckDelay,
+        RS: ResetPins<E>,
+    > CmsisDap<'a, B, E, TDI, TCK, TMS, TDO, D, RS>
+{
+    /// Attach a delay, so that TCK stays under the frequency set by the host with DAP_SWJ_Clock.
+    pub fn with_delay<D2: TckDelay>(
+        self,
+        delay: D2,
+    ) -> CmsisDap<'a, B, E, TDI, TCK, TMS, TDO, D2, RS> {
+        self.map_port(|port| port.with_delay(delay))
+    }
+
+    /// Attach the nTRST and nSRST lines, so that the host can drive them with DAP_SWJ_Pins and reset the target with DAP_ResetTarget.
+    /// Both lines are released on a USB reset.
+    pub fn with_reset_pins<RS2: ResetPins<E>>(
+        self,
+        reset_pins: RS2,
+    ) -> CmsisDap<'a, B, E, TDI, TCK, TMS, TDO, D, RS2> {
+        self.map_port(|port| port.with_reset_pins(reset_pins))
+    }
+
+    /// Mark the lines that pass through inverting buffers. The JTAG state is tracked on the logic levels.
+    pub fn with_polarity(mut self, polarity: Polarity) -> Self {
+        self.port.set_polarity(polarity);
+        self
+    }
+
+    /// Swap the port for one built from it, keeping everything else
+    fn map_port<D2: TckDelay, RS2: ResetPins<E>>(
+        self,
+        f: impl FnOnce(
+            Port<E, TDI, TCK, TMS, TDO, (), (), D, (), RS>,
+        ) -> Port<E, TDI, TCK, TMS, TDO, (), (), D2, (), RS2>,
+    ) -> CmsisDap<'a, B, E, TDI, TCK, TMS, TDO, D2, RS2> {
+        CmsisDap {
+            iface: self.iface,
+            interface_string: self.interface_string,
+            alt_setting: self.alt_setting,
+            read_ep: self.read_ep,
+            write_ep: self.write_ep,
+            port: f(self.port),
+            ir_lengths: self.ir_lengths,
+            device_count: self.device_count,
+            idle_cycles: self.idle_cycles,
+            wait_retry: self.wait_retry,
+            match_retry: self.match_retry,
+            match_mask: self.match_mask,
+            pins: self.pins,
+            request: self.request,
+            request_len: self.request_len,
+            response: self.response,
+            response_len: self.response_len,
+            error: self.error,
+        }
+    }
+
+    /// The TCK frequency set by the host with DAP_SWJ_Clock, in Hz
+    pub fn tck_frequency(&self) -> u32 {
+        self.port.tck_frequency()
+    }
+
+    /// Read a command packet from the host, once the response to the last one has been sent.
+    pub fn read(&mut self) -> Result<usize, BlasterError<E>> {
+        if self.request_len != 0 || self.response_len != 0 {
+            return Err(UsbError::WouldBlock.into());
+        }
+        let amount = self.read_ep.read(&mut self.request)?;
+        self.request_len = amount;
+        Ok(amount)
+    }
+
+    /// Write the response to the last command to the host.
+    pub fn write(&mut self) -> Result<usize, BlasterError<E>> {
+        if self.response_len == 0 {
+            return Err(UsbError::WouldBlock.into());
+        }
+        let amount = self.write_ep.write(&self.response[..self.response_len])?;
+        self.response_len = 0;
+        Ok(amount)
+    }
+
+    /// Run the command that was read and prepare its response.
+    /// If a GPIO error occurs, DAP_ERROR is sent back and the JTAG state is undefined until the host reconnects.
+    pub fn handle(&mut self) -> Result<(), BlasterError<E>> {
+        if self.request_len == 0 || self.response_len != 0 {
+            return Ok(());
+        }
+        let command = self.request[0];
+        self.response[0] = command;
+        self.response_len = 1;
+        let res = self.execute(command);
+        self.request_len = 0;
+        if let Err(err) = res {
+            self.response[1] = DAP_ERROR;
+            self.response_len = 2;
+            return Err(err);
+        }
+        // DAP_TransferAbort has no response
+        if command == ID_DAP_TRANSFER_ABORT {
+            self.response_len = 0;
+        }
+        Ok(())
+    }
+
+    /// Poll the USB device, read a command from the host, run it and write the response.
+    /// Use [`CmsisDap::read`], [`CmsisDap::handle`] and [`CmsisDap::write`] instead if the USB device has other classes to poll.
+    pub fn poll(&mut self, usb_dev: &mut UsbDevice<'a, B>) -> Result<PollSummary, BlasterError<E>> {
+        usb_dev.poll(&mut [self]);
+        let mut summary = PollSummary {
+            received: would_block(self.read())?,
+            ..PollSummary::default()
+        };
+        let before = self.request_len;
+        self.handle()?;
+        summary.handled = before - self.request_len;
+        summary.sent = would_block(self.write())?;
+        summary.pending = self.request_len;
+        Ok(summary)
+    }
+
+    /// Take the first error raised while handling a USB reset.
+    pub fn take_error(&mut self) -> Option<BlasterError<E>> {
+        self.error.take()
+    }
+
+    fn execute(&mut self, command: u8) -> Result<(), BlasterError<E>> {
+        match command {
+            ID_DAP_INFO => self.info(),
+            ID_DAP_HOST_STATUS => self.push(DAP_OK),
+            ID_DAP_CONNECT => {
+                let port = self.arg(1);
+                if port == DAP_PORT_DEFAULT || port == DAP_PORT_JTAG {
+                    self.port
+                        .set_output_enable(true)
+                        .map_err(BlasterError::Pin)?;
+                    self.push(DAP_PORT_JTAG);
+                } else {
+                    self.push(DAP_PORT_DEFAULT);
+                }
+            }
+            ID_DAP_DISCONNECT => {
+                self.port
+                    .set_output_enable(false)
+                    .map_err(BlasterError::Pin)?;
+                self.push(DAP_OK);
+            }
+            ID_DAP_TRANSFER_CONFIGURE => {
+                self.idle_cycles = self.arg(1);
+                self.wait_retry = self.arg_u16(2);
+                self.match_retry = self.arg_u16(4);
+                self.push(DAP_OK);
+            }
+            ID_DAP_TRANSFER => self.transfer()?,
+            ID_DAP_TRANSFER_BLOCK => self.transfer_block()?,
+            ID_DAP_TRANSFER_ABORT => {}
+            ID_DAP_WRITE_ABORT => {
+                let index = self.arg(1) as usize;
+                if index < self.device_count {
+                    self.write_ir(index, JTAG_ABORT)?;
+                    self.scan_dr(index, 0, self.arg_u32(2))?;
+                    self.push(DAP_OK);
+                } else {
+                    self.push(DAP_ERROR);
+                }
+            }
+            ID_DAP_DELAY => {
+                self.port.wait_us(self.arg_u16(1) as u32);
+                self.push(DAP_OK);
+            }
+            ID_DAP_RESET_TARGET => {
+                let executed = self.port.pulse_nsrst().map_err(BlasterError::Pin)?;
+                self.push(DAP_OK);
+                self.push(executed as u8);
+            }
+            ID_DAP_SWJ_PINS => {
+                let (output, select) = (self.arg(1), self.arg(2));
+                self.pins = (self.pins & !select) | (output & select);
+                self.port.drive(
+                    self.pins & DAP_SWJ_TCK != 0,
+                    self.pins & DAP_SWJ_TDI != 0,
+                    self.pins & DAP_SWJ_TMS != 0,
+                )?;
+                if select & DAP_SWJ_NTRST != 0
+                    && !self
+                        .port
+                        .set_ntrst(output & DAP_SWJ_NTRST == 0)
+                        .map_err(BlasterError::Pin)?
+                {
+                    self.pins |= DAP_SWJ_NTRST;
+                }
+                if select & DAP_SWJ_NRESET != 0
+                    && !self
+                        .port
+                        .set_nsrst(output & DAP_SWJ_NRESET == 0)
+                        .map_err(BlasterError::Pin)?
+                {
+                    self.pins |= DAP_SWJ_NRESET;
+                }
+                // Wait for the selected pins to read back as requested, or for the time given by the host to run out
+                let mut wait = self.arg_u32(3).min(DAP_SWJ_MAX_WAIT_US);
+                let mut pins = self.swj_pins()?;
+                while (pins ^ output) & select != 0 && wait != 0 {
+                    self.port.wait_us(1);
+                    wait -= 1;
+                    pins = self.swj_pins()?;
+                }
+                self.push(pins);
+            }
+            ID_DAP_SWJ_CLOCK => match self.arg_u32(1) {
+                0 => self.push(DAP_ERROR),
+                clock => {
+                    self.port.set_tck_frequency(clock);
+                    self.push(DAP_OK);
+                }
+            },
+            ID_DAP_SWJ_SEQUENCE => {
+                let bits = match self.arg(1) {
+                    0 => 256,
+                    bits => bits as usize,
+                };
+                for n in 0..bits {
+                    let tms = self.arg(2 + n / 8) >> (n % 8) & 1 != 0;
+                    self.port.clock_tms(tms, true)?;
+                }
+                self.push(DAP_OK);
+            }
+            ID_DAP_JTAG_SEQUENCE => self.jtag_sequence()?,
+            ID_DAP_JTAG_CONFIGURE => {
+                let count = self.arg(1) as usize;
+                if count == 0 || count > DAP_MAX_DEVICES || (0..count).any(|n| self.arg(2 + n) == 0)
+                {
+                    self.push(DAP_ERROR);
+                } else {
+                    for n in 0..count {
+                        self.ir_lengths[n] = self.arg(2 + n);
+                    }
+                    self.device_count = count;
+                    self.push(DAP_OK);
+                }
+            }
+            ID_DAP_JTAG_IDCODE => {
+                let index = self.arg(1) as usize;
+                if index < self.device_count {
+                    self.write_ir(index, JTAG_IDCODE)?;
+                    let idcode = self.read_idcode(index)?;
+                    self.push(DAP_OK);
+                    self.push_u32(idcode);
+                } else {
+                    self.push(DAP_ERROR);
+                }
+            }
+            _ => {
+                self.response[0] = ID_DAP_INVALID;
+            }
+        }
+        Ok(())
+    }
+
+    fn info(&mut self) {
+        let value: &[u8] = match self.arg(1) {
+            DAP_ID_CMSIS_DAP_VER => b"2.1.0\0",
+            DAP_ID_FW_VER => concat!(env!("CARGO_PKG_VERSION"), "\0").as_bytes(),
+            DAP_ID_CAPABILITIES => &[DAP_CAP_JTAG],
+            DAP_ID_PACKET_COUNT => &[1],
+            DAP_ID_PACKET_SIZE => &[DAP_PACKET_SIZE as u8, 0],
+            // The vendor, product and serial number come from the USB device descriptor
+            DAP_ID_VENDOR | DAP_ID_PRODUCT | DAP_ID_SER_NUM => &[],
+            _ => &[],
+        };
+        self.push(value.len() as u8);
+        for &byte in value {
+            self.push(byte);
+        }
+    }
+
+    /// [DAP_JTAG_Sequence](https://arm-software.github.io/CMSIS_5/DAP/html/group__DAP__JTAG__Sequence.html): clock TDI with TMS held, capturing TDO if asked
+    fn jtag_sequence(&mut self) -> Result<(), BlasterError<E>> {
+        /// TCK cycles in the sequence, where 0 means 64
+        const SEQUENCE_TCK: u8 = 0x3F;
+        const SEQUENCE_TMS: u8 = 0x40;
+        const SEQUENCE_TDO: u8 = 0x80;
+
+        self.push(DAP_OK);
+        let count = self.arg(1);
+        let mut offset = 2;
+        for _ in 0..count {
+            let info = self.arg(offset);
+            offset += 1;
+            let cycles = match info & SEQUENCE_TCK {
+                0 => 64,
+                cycles => cycles as usize,
+            };
+            let tms = info & SEQUENCE_TMS != 0;
+            let mut tdo = 0u8;
+            for n in 0..cycles {
+                let tdi = self.arg(offset + n / 8) >> (n % 8) & 1 != 0;
+                if self.port.clock_tms(tms, tdi)? {
+                    tdo |= 1 << (n % 8);
+                }
+                if n % 8 == 7 || n == cycles - 1 {
+                    if info & SEQUENCE_TDO != 0 {
+                        self.push(tdo);
+                    }
+                    tdo = 0;
+                }
+            }
+            offset += cycles.div_ceil(8);
+        }
+        Ok(())
+    }
+
+    /// [DAP_Transfer](https://arm-software.github.io/CMSIS_5/DAP/html/group__DAP__Transfer.html) through the JTAG-DP of the device at the given index.
+    /// Reads are posted, so the result of each one is collected by the next scan.
+    fn transfer(&mut self) -> Result<(), BlasterError<E>> {
+        let index = self.arg(1) as usize;
+        let count = self.arg(2);
+        let mut offset = 3;
+        let mut done = 0u8;
+        let mut response = 0u8;
+        let mut ir = 0;
+        let mut post_read = false;
+        let mut check_write = false;
+        // Count and response, filled in at the end
+        self.push(0);
+        self.push(0);
+        if index >= self.device_count {
+            return Ok(());
+        }
+        while done < count {
+            let request = self.arg(offset);
+            offset += 1;
+            let request_ir = if request & DAP_TRANSFER_APNDP != 0 {
+                JTAG_APACC
+            } else {
+                JTAG_DPACC
+            };
+            if request & DAP_TRANSFER_RNW != 0 {
+                if post_read {
+                    // Collect the previous read, posting this one if it is a plain AP read
+                    let data;
+                    if ir == request_ir
+                        && request & (DAP_TRANSFER_APNDP | DAP_TRANSFER_MATCH_VALUE)
+                            == DAP_TRANSFER_APNDP
+                    {
+                        let (ack, captured) = self.scan_retry(index, request, 0)?;
+                        response = ack;
+                        data = captured;
+                    } else {
+                        self.select(index, &mut ir, JTAG_DPACC)?;
+                        let (ack, captured) =
+                            self.scan_retry(index, DP_RDBUFF | DAP_TRANSFER_RNW, 0)?;
+                        response = ack;
+                        data = captured;
+                        post_read = false;
+                    }
+                    if response != DAP_TRANSFER_OK {
+                        break;
+                    }
+                    if !self.push_u32(data) {
+                        // The read whose data does not fit is not done
+                        done -= 1;
+                        response = DAP_TRANSFER_ERROR;
+                        break;
+                    }
+                    check_write = false;
+                }
+                if request & DAP_TRANSFER_MATCH_VALUE != 0 {
+                    let match_value = self.arg_u32(offset);
+                    offset += 4;
+                    self.select(index, &mut ir, request_ir)?;
+                    let (ack, _) = self.scan_retry(index, request, 0)?;
+                    response = ack;
+                    if response != DAP_TRANSFER_OK {
+                        break;
+                    }
+                    let mut retry = self.match_retry;
+                    loop {
+                        let (ack, data) = self.scan_retry(index, request, 0)?;
+                        response = ack;
+                        if response != DAP_TRANSFER_OK {
+                            break;
+                        }
+                        if data & self.match_mask == match_value {
+                            break;
+                        }
+                        if retry == 0 {
+                            response |= DAP_TRANSFER_MISMATCH;
+                            break;
+                        }
+                        retry -= 1;
+                    }
+                    if response != DAP_TRANSFER_OK {
+                        break;
+                    }
+                } else if !post_read {
+                    self.select(index, &mut ir, request_ir)?;
+                    let (ack, _) = self.scan_retry(index, request, 0)?;
+                    response = ack;
+                    if response != DAP_TRANSFER_OK {
+                        break;
+                    }
+                    post_read = true;
+                }
+            } else {
+                if post_read {
+                    self.select(index, &mut ir, JTAG_DPACC)?;
+                    let (ack, data) = self.scan_retry(index, DP_RDBUFF | DAP_TRANSFER_RNW, 0)?;
+                    response = ack;
+                    if response != DAP_TRANSFER_OK {
+                        break;
+                    }
+                    if !self.push_u32(data) {
+                        done -= 1;
+                        response = DAP_TRANSFER_ERROR;
+                        break;
+                    }
+                    post_read = false;
+                }
+                let data = self.arg_u32(offset);
+                offset += 4;
+                if request & DAP_TRANSFER_MATCH_MASK != 0 {
+                    self.match_mask = data;
+                    response = DAP_TRANSFER_OK;
+                } else {
+                    self.select(index, &mut ir, request_ir)?;
+                    let (ack, _) = self.scan_retry(index, request, data)?;
+                    response = ack;
+                    if response != DAP_TRANSFER_OK {
+                        break;
+                    }
+                    check_write = true;
+                }
+            }
+            done += 1;
+        }
+        if response == DAP_TRANSFER_OK {
+            if post_read {
+                self.select(index, &mut ir, JTAG_DPACC)?;
+                let (ack, data) = self.scan_retry(index, DP_RDBUFF | DAP_TRANSFER_RNW, 0)?;
+                response = ack;
+                if response == DAP_TRANSFER_OK && !self.push_u32(data) {
+                    done -= 1;
+                    response = DAP_TRANSFER_ERROR;
+                }
+            } else if check_write {
+                self.select(index, &mut ir, JTAG_DPACC)?;
+                let (ack, _) = self.scan_retry(index, DP_RDBUFF | DAP_TRANSFER_RNW, 0)?;
+                response = ack;
+            }
+        }
+        self.response[1] = done;
+        self.response[2] = response;
+        Ok(())
+    }
+
+    /// [DAP_TransferBlock](https://arm-software.github.io/CMSIS_5/DAP/html/group__DAP__TransferBlock.html): read or write one register many times
+    fn transfer_block(&mut self) -> Result<(), BlasterError<E>> {
+        let index = self.arg(1) as usize;
+        let count = self.arg_u16(2);
+        let request = self.arg(4);
+        let mut offset = 5;
+        let mut done = 0u16;
+        let mut response = 0u8;
+        let mut ir = 0;
+        // Count and response, filled in at the end
+        self.push(0);
+        self.push(0);
+        self.push(0);
+        if index < self.device_count && count != 0 {
+            let request_ir = if request & DAP_TRANSFER_APNDP != 0 {
+                JTAG_APACC
+            } else {
+                JTAG_DPACC
+            };
+            self.select(index, &mut ir, request_ir)?;
+            if request & DAP_TRANSFER_RNW != 0 {
+                // Post the first read, then collect each result with the next scan
+                let (ack, _) = self.scan_retry(index, request, 0)?;
+                response = ack;
+                while response == DAP_TRANSFER_OK && done < count {
+                    let (ack, data) = if done == count - 1 {
+                        self.select(index, &mut ir, JTAG_DPACC)?;
+                        self.scan_retry(index, DP_RDBUFF | DAP_TRANSFER_RNW, 0)?
+                    } else {
+                        self.scan_retry(index, request, 0)?
+                    };
+                    response = ack;
+                    if response != DAP_TRANSFER_OK {
+                        break;
+                    }
+                    if !self.push_u32(data) {
+                        response = DAP_TRANSFER_ERROR;
+                        break;
+                    }
+                    done += 1;
+                }
+            } else {
+                response = DAP_TRANSFER_OK;
+                while done < count {
+                    let data = self.arg_u32(offset);
+                    offset += 4;
+                    let (ack, _) = self.scan_retry(index, request, data)?;
+                    response = ack;
+                    if response != DAP_TRANSFER_OK {
+                        break;
+                    }
+                    done += 1;
+                }
+                if response == DAP_TRANSFER_OK {
+                    // Check the last write
+                    self.select(index, &mut ir, JTAG_DPACC)?;
+                    let (ack, _) = self.scan_retry(index, DP_RDBUFF | DAP_TRANSFER_RNW, 0)?;
+                    response = ack;
+                }
+            }
+        }
+        self.response[1..3].copy_from_slice(&done.to_le_bytes());
+        self.response[3] = response;
+        Ok(())
+    }
+
+    /// Load `instruction` into the IR of the device at the given index, unless it is already there
+    fn select(
+        &mut self,
+        index: usize,
+        ir: &mut u32,
+        instruction: u32,
+    ) -> Result<(), BlasterError<E>> {
+        if *ir != instruction {
+            *ir = instruction;
+            self.write_ir(index, instruction)?;
+        }
+        Ok(())
+    }
+
+    /// Scan a DPACC or APACC request, retrying while the target answers WAIT
+    fn scan_retry(
+        &mut self,
+        index: usize,
+        request: u8,
+        data: u32,
+    ) -> Result<(u8, u32), BlasterError<E>> {
+        let mut retry = self.wait_retry;
+        loop {
+            let (ack, captured) = self.scan_dr(index, request, data)?;
+            if ack != DAP_TRANSFER_WAIT || retry == 0 {
+                return Ok((ack, captured));
+            }
+            retry -= 1;
+        }
+    }
+
+    /// Scan the 35 bit DPACC or APACC register of the device at the given index, starting and ending in Run-Test/Idle.
+    /// Returns the acknowledge as a transfer response, and the data captured by the previous read.
+    fn scan_dr(
+        &mut self,
+        index: usize,
+        request: u8,
+        data: u32,
+    ) -> Result<(u8, u32), BlasterError<E>> {
+        let after = self.device_count - index - 1;
+        // Select-DR-Scan, Capture-DR, Shift-DR
+        self.port.clock_tms(true, true)?;
+        self.port.clock_tms(false, true)?;
+        self.port.clock_tms(false, true)?;
+        for _ in 0..index {
+            self.port.clock_tms(false, true)?;
+        }
+        // RnW and A[3:2] go out while the acknowledge comes back
+        let mut ack = 0u8;
+        for n in 0..3 {
+            if self.port.clock_tms(false, request >> (n + 1) & 1 != 0)? {
+                ack |= 1 << n;
+            }
+        }
+        let ack = match ack {
+            0b010 => DAP_TRANSFER_OK,
+            0b001 => DAP_TRANSFER_WAIT,
+            _ => DAP_TRANSFER_NO_ACK,
+        };
+        let mut captured = 0u32;
+        if ack == DAP_TRANSFER_OK {
+            for n in 0..32 {
+                let last = n == 31 && after == 0;
+                if self.port.clock_tms(last, data >> n & 1 != 0)? {
+                    captured |= 1 << n;
+                }
+            }
+            for n in 0..after {
+                self.port.clock_tms(n == after - 1, true)?;
+            }
+        } else {
+            // Exit1-DR
+            self.port.clock_tms(true, true)?;
+        }
+        // Update-DR, Run-Test/Idle
+        self.port.clock_tms(true, true)?;
+        self.port.clock_tms(false, true)?;
+        for _ in 0..self.idle_cycles {
+            self.port.clock_tms(false, true)?;
+        }
+        Ok((ack, captured))
+    }
+
+    /// Load `instruction` into the IR of the device at the given index and BYPASS into the others, starting and ending in Run-Test/Idle
+    fn write_ir(&mut self, index: usize, instruction: u32) -> Result<(), BlasterError<E>> {
+        let before: usize = self.ir_lengths[..index]
+            .iter()
+            .map(|&len| len as usize)
+            .sum();
+        let after: usize = self.ir_lengths[index + 1..self.device_count]
+            .iter()
+            .map(|&len| len as usize)
+            .sum();
+        let length = self.ir_lengths[index] as usize;
+        // Select-DR-Scan, Select-IR-Scan, Capture-IR, Shift-IR
+        self.port.clock_tms(true, true)?;
+        self.port.clock_tms(true, true)?;
+        self.port.clock_tms(false, true)?;
+        self.port.clock_tms(false, true)?;
+        for _ in 0..before {
+            self.port.clock_tms(false, true)?;
+        }
+        for n in 0..length {
+            let last = n == length - 1 && after == 0;
+            self.port
+                .clock_tms(last, n < 32 && instruction >> n & 1 != 0)?;
+        }
+        for n in 0..after {
+            self.port.clock_tms(n == after - 1, true)?;
+        }
+        // Update-IR, Run-Test/Idle
+        self.port.clock_tms(true, true)?;
+        self.port.clock_tms(false, true)?;
+        Ok(())
+    }
+
+    /// Read the 32 bit IDCODE of the device at the given index, once the IDCODE instruction is loaded
+    fn read_idcode(&mut self, index: usize) -> Result<u32, BlasterError<E>> {
+        // Select-DR-Scan, Capture-DR, Shift-DR
+        self.port.clock_tms(true, true)?;
+        self.port.clock_tms(false, true)?;
+        self.port.clock_tms(false, true)?;
+        for _ in 0..index {
+            self.port.clock_tms(false, true)?;
+        }
+        let mut idcode = 0u32;
+        for n in 0..32 {
+            if self.port.clock_tms(n == 31, true)? {
+                idcode |= 1 << n;
+            }
+        }
+        // Update-DR, Run-Test/Idle
+        self.port.clock_tms(true, true)?;
+        self.port.clock_tms(false, true)?;
+        Ok(idcode)
+    }
+
+    /// A byte of the request, or 0 past its end
+    /// The DAP_SWJ_Pins levels: the driven lines as last set, and TDO as sampled
+    fn swj_pins(&self) -> Result<u8, BlasterError<E>> {
+        let tdo = if self.port.tdo().map_err(BlasterError::Pin)? {
+            DAP_SWJ_TDO
+        } else {
+            0
+        };
+        Ok((self.pins & DAP_SWJ_DRIVEN) | tdo)
+    }
+
+    fn arg(&self, offset: usize) -> u8 {
+        if offset < self.request_len {
+            self.request[offset]
+        } else {
+            0
+        }
+    }
+
+    /// Whether `req` is the standard `request` addressed to this interface
+    fn is_interface_request(&self, req: &control::Request, request: u8) -> bool {
+        req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Interface
+            && req.request == request
+            && req.index == u8::from(self.iface) as u16
+    }
+
+    fn arg_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.arg(offset), self.arg(offset + 1)])
+    }
+
+    fn arg_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([
+            self.arg(offset),
+            self.arg(offset + 1),
+            self.arg(offset + 2),
+            self.arg(offset + 3),
+        ])
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.response_len < self.response.len() {
+            self.response[self.response_len] = byte;
+            self.response_len += 1;
+        }
+    }
+
+    /// Append a word to the response, returning false if it does not fit
+    fn push_u32(&mut self, word: u32) -> bool {
+        if self.response_len + 4 > self.response.len() {
+            return false;
+        }
+        self.response[self.response_len..self.response_len + 4]
+            .copy_from_slice(&word.to_le_bytes());
+        self.response_len += 4;
+        true
+    }
+}
+
+impl<
+        B,
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+        D: TckDelay,
+        RS: ResetPins<E>,
+    > UsbClass<B> for CmsisDap<'_, B, E, TDI, TCK, TMS, TDO, D, RS>
+where
+    B: UsbBus,
+{
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        // CMSIS-DAP v2 expects the OUT endpoint first
+        writer.interface(self.iface, 0xFF, 0x00, 0x00)?;
+        writer.endpoint(&self.read_ep)?;
+        writer.endpoint(&self.write_ep)?;
+        // usb-device writes interfaces without a string, so the interface is described again as alternate setting 1
+        // with the "CMSIS-DAP" string that hosts look for
+        writer.write(
+            descriptor_type::INTERFACE,
+            &[
+                self.iface.into(),
+                DAP_ALT_SETTING_NAMED,
+                2,
+                0xFF,
+                0x00,
+                0x00,
+                self.interface_string.into(),
+            ],
+        )?;
+        write_endpoint(writer, &self.read_ep)?;
+        write_endpoint(writer, &self.write_ep)
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if self.is_interface_request(&req, control::Request::GET_INTERFACE) {
+            xfer.accept_with(&[self.alt_setting]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if self.is_interface_request(&req, control::Request::SET_INTERFACE) {
+            if req.value <= DAP_ALT_SETTING_NAMED as u16 {
+                self.alt_setting = req.value as u8;
+                xfer.accept().ok();
+            } else {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        if index == self.interface_string {
+            Some(DAP_INTERFACE_STRING)
+        } else {
+            None
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Err(err) = self.port.reset() {
+            if self.error.is_none() {
+                self.error = Some(BlasterError::Pin(err));
+            }
+        }
+        // The port releases the reset lines
+        self.pins |= DAP_SWJ_NTRST | DAP_SWJ_NRESET;
+        self.request_len = 0;
+        self.response_len = 0;
+        self.alt_setting = 0;
+    }
+}
+
+/// Write an endpoint descriptor outside of the endpoint count kept by usb-device, for an alternate setting
+fn write_endpoint<B: UsbBus, D: EndpointDirection>(
+    writer: &mut DescriptorWriter,
+    endpoint: &Endpoint<B, D>,
+) -> usb_device::Result<()> {
+    let mps = endpoint.max_packet_size();
+    writer.write(
+        descriptor_type::ENDPOINT,
+        &[
+            endpoint.address().into(),
+            endpoint.ep_type() as u8,
+            mps as u8,
+            (mps >> 8) as u8,
+            endpoint.interval(),
+        ],
+    )
+}
@@ -0,0 +1,42 @@
+use hal::blocking::delay::DelayUs;
+
+/// Waits between TCK edges, so that TCK stays under a target frequency, and times reset pulses.
+/// Use `()` to toggle TCK as fast as the pins allow.
+pub trait TckDelay {
+    /// Wait at least `ns` nanoseconds
+    fn delay_ns(&mut self, ns: u32);
+}
+
+impl TckDelay for () {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// A TCK delay from an `embedded_hal` microsecond delay, rounded up to whole microseconds
+pub struct UsDelay<D>(pub D);
+
+impl<D: DelayUs<u32>> TckDelay for UsDelay<D> {
+    fn delay_ns(&mut self, ns: u32) {
+        self.0.delay_us(ns.div_ceil(1000));
+    }
+}
+
+/// A TCK delay that busy-waits for a number of CPU cycles, such as `cortex_m::asm::delay`.
+/// Finer than a microsecond timer, for TCK frequencies in the MHz range.
+pub struct CycleDelay<F> {
+    cpu_hz: u32,
+    spin: F,
+}
+
+impl<F: FnMut(u32)> CycleDelay<F> {
+    /// `spin` waits for at least the given number of cycles of a CPU running at `cpu_hz`
+    pub fn new(cpu_hz: u32, spin: F) -> Self {
+        CycleDelay { cpu_hz, spin }
+    }
+}
+
+impl<F: FnMut(u32)> TckDelay for CycleDelay<F> {
+    fn delay_ns(&mut self, ns: u32) {
+        let cycles = (ns as u64 * self.cpu_hz as u64).div_ceil(1_000_000_000);
+        (self.spin)(cycles.min(u32::MAX as u64) as u32);
+    }
+}
@@ -0,0 +1,26 @@
+use usb_device::UsbError;
+
+/// Errors raised by the blaster. `E` is the error type of the pins.
+#[derive(Debug)]
+pub enum BlasterError<E> {
+    /// The USB peripheral failed to transfer data or complete a control request
+    Usb(UsbError),
+    /// A pin could not be driven or sampled. The JTAG state is undefined until the next reset.
+    Pin(E),
+    /// The EEPROM storage failed to commit or erase an image written by the host
+    Storage,
+    /// The host sent this vendor request with invalid parameters, so it was rejected
+    InvalidRequest(u8),
+    /// RTCK did not follow TCK within the timeout. The JTAG state is undefined until the next reset.
+    ReturnClockTimeout,
+    /// The SPI peripheral failed to shift data. The JTAG state is undefined until the next reset.
+    Spi,
+    /// The UART failed to receive or send a byte, which was dropped
+    Uart,
+}
+
+impl<E> From<UsbError> for BlasterError<E> {
+    fn from(err: UsbError) -> Self {
+        BlasterError::Usb(err)
+    }
+}
@@ -1,10 +1,379 @@
-pub const ROM: [u8; 128] = [
-    0x0, 0x0, 0xfb, 0x9, 0x1, 0x60, 0x0, 0x4, 0x80, 0xe1, 0x1c, 0x0, 0x0, 0x2, 0x94, 0xe, 0xa2,
-    0x18, 0xba, 0x12, 0xe, 0x3, 0x41, 0x0, 0x6c, 0x0, 0x74, 0x0, 0x65, 0x0, 0x72, 0x0, 0x61, 0x0,
-    0x18, 0x3, 0x55, 0x0, 0x53, 0x0, 0x42, 0x0, 0x2d, 0x0, 0x42, 0x0, 0x6c, 0x0, 0x61, 0x0, 0x73,
-    0x0, 0x74, 0x0, 0x65, 0x0, 0x72, 0x0, 0x12, 0x3, 0x31, 0x0, 0x32, 0x0, 0x33, 0x0, 0x34, 0x0,
-    0x35, 0x0, 0x36, 0x0, 0x37, 0x0, 0x38, 0x0, 0x2, 0x3, 0x1, 0x0, 0x52, 0x45, 0x56, 0x42, 0x0,
-    0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
-    0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
-    0x0, 0x0, 0x0, 0x7a, 0x96,
-];
+use core::convert::Infallible;
+
+use usb_device::prelude::UsbVidPid;
+
+use crate::{ALTERA_BLASTER_USB_VID_PID, FTDI_FT2232H_USB_VID_PID};
+
+/// Size of the 93C46 EEPROM attached to the FT245, in bytes
+pub const EEPROM_SIZE: usize = 128;
+
+/// The EEPROM image of an Altera USB Blaster
+pub const ROM: [u8; EEPROM_SIZE] = EepromBuilder::new().build();
+
+/// The EEPROM image of a stock FT2232H
+pub const FT2232H_ROM: [u8; EEPROM_SIZE] = EepromBuilder::ft2232h().build();
+
+/// Byte offset of the first string descriptor
+const STRINGS_START: usize = 0x14;
+/// Byte offset of the first string descriptor on an FT2232H, following its larger header
+const FT2232H_STRINGS_START: usize = 0x1A;
+/// Byte offset of the EEPROM chip type on an FT2232H
+const FT2232H_CHIP_TYPE: usize = 0x18;
+/// The chip type of a 93C46
+const CHIP_93C46: u8 = 0x46;
+/// Byte offset of the checksum word, which is the last word of the EEPROM
+pub const CHECKSUM_OFFSET: usize = EEPROM_SIZE - 2;
+/// String offsets in the header are stored with this bit set
+const STRING_OFFSET_FLAG: u8 = 0x80;
+/// USB string descriptor type
+const STRING_DESCRIPTOR: u8 = 0x03;
+
+/// Bus powered, as required by USB 1.1 and later
+const ATTRIBUTES_BUS_POWERED: u8 = 0b1000_0000;
+const ATTRIBUTES_SELF_POWERED: u8 = 0b0100_0000;
+const ATTRIBUTES_REMOTE_WAKEUP: u8 = 0b0010_0000;
+
+/// [Chip configuration](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L2718): pull down in suspend
+const CONFIG_PULL_DOWN: u8 = 0b0000_0100;
+/// Report the serial number string
+const CONFIG_USE_SERIAL: u8 = 0b0000_1000;
+/// Report the USB version stored in the EEPROM
+const CONFIG_USE_USB_VERSION: u8 = 0b0001_0000;
+
+/// Data following the string descriptors on an Altera USB Blaster: an empty string descriptor, then the hardware revision
+const ALTERA_USER_AREA: [u8; 8] = [0x02, 0x03, 0x01, 0x00, b'R', b'E', b'V', b'B'];
+
+/// The FTDI chip whose EEPROM layout is built
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Chip {
+    Ft245,
+    Ft2232h,
+}
+
+/// Builds the FT245 EEPROM image that is read by the host, including the checksum.
+/// The defaults describe an Altera USB Blaster, so that Quartus recognizes the device.
+/// [`EepromBuilder::ft2232h`] builds the layout of an FT2232H instead, for [`Mpsse`](crate::Mpsse).
+///
+/// Every method is `const`, so the image can be built at compile time.
+#[derive(Clone, Copy, Debug)]
+pub struct EepromBuilder<'a> {
+    chip: Chip,
+    vid: u16,
+    pid: u16,
+    device_release: u16,
+    usb_version: u16,
+    manufacturer: &'a str,
+    product: &'a str,
+    serial_number: &'a str,
+    max_power_ma: u16,
+    self_powered: bool,
+    remote_wakeup: bool,
+    user_area: &'a [u8],
+}
+
+impl<'a> EepromBuilder<'a> {
+    pub const fn new() -> EepromBuilder<'a> {
+        EepromBuilder {
+            chip: Chip::Ft245,
+            vid: ALTERA_BLASTER_USB_VID_PID.0,
+            pid: ALTERA_BLASTER_USB_VID_PID.1,
+            device_release: 0x0400,
+            usb_version: 0x0200,
+            manufacturer: "Altera",
+            product: "USB-Blaster",
+            serial_number: "12345678",
+            max_power_ma: 450,
+            self_powered: false,
+            remote_wakeup: false,
+            user_area: &ALTERA_USER_AREA,
+        }
+    }
+
+    /// The defaults of a stock FT2232H, with the FT2232H layout: channels A and B are UARTs using the D2XX driver,
+    /// every pin drives 4 mA and the EEPROM is a 93C46. The USB version is not stored.
+    pub const fn ft2232h() -> EepromBuilder<'a> {
+        EepromBuilder {
+            chip: Chip::Ft2232h,
+            vid: FTDI_FT2232H_USB_VID_PID.0,
+            pid: FTDI_FT2232H_USB_VID_PID.1,
+            device_release: 0x0700,
+            usb_version: 0x0200,
+            manufacturer: "FTDI",
+            product: "Dual RS232-HS",
+            serial_number: "",
+            max_power_ma: 100,
+            self_powered: false,
+            remote_wakeup: false,
+            user_area: &[],
+        }
+    }
+
+    pub const fn vid_pid(mut self, vid_pid: UsbVidPid) -> Self {
+        self.vid = vid_pid.0;
+        self.pid = vid_pid.1;
+        self
+    }
+
+    /// Device release number in BCD, e.g. 0x0400
+    pub const fn device_release(mut self, device_release: u16) -> Self {
+        self.device_release = device_release;
+        self
+    }
+
+    /// USB specification version in BCD, e.g. 0x0200. Not stored by the FT2232H.
+    pub const fn usb_version(mut self, usb_version: u16) -> Self {
+        self.usb_version = usb_version;
+        self
+    }
+
+    pub const fn manufacturer(mut self, manufacturer: &'a str) -> Self {
+        self.manufacturer = manufacturer;
+        self
+    }
+
+    pub const fn product(mut self, product: &'a str) -> Self {
+        self.product = product;
+        self
+    }
+
+    /// An empty serial number is not reported to the host
+    pub const fn serial_number(mut self, serial_number: &'a str) -> Self {
+        self.serial_number = serial_number;
+        self
+    }
+
+    /// Maximum current drawn from the bus in milliamps, from 0 to 510
+    pub const fn max_power(mut self, max_power_ma: u16) -> Self {
+        self.max_power_ma = max_power_ma;
+        self
+    }
+
+    pub const fn self_powered(mut self, self_powered: bool) -> Self {
+        self.self_powered = self_powered;
+        self
+    }
+
+    pub const fn remote_wakeup(mut self, remote_wakeup: bool) -> Self {
+        self.remote_wakeup = remote_wakeup;
+        self
+    }
+
+    /// Data stored after the string descriptors, e.g. board metadata
+    pub const fn user_area(mut self, user_area: &'a [u8]) -> Self {
+        self.user_area = user_area;
+        self
+    }
+
+    /// Generate the image. Panics if the strings and user area do not fit.
+    pub const fn build(&self) -> [u8; EEPROM_SIZE] {
+        assert!(self.max_power_ma <= 510, "max power must be at most 510 mA");
+
+        let mut image = [0u8; EEPROM_SIZE];
+        image[0x02] = self.vid as u8;
+        image[0x03] = (self.vid >> 8) as u8;
+        image[0x04] = self.pid as u8;
+        image[0x05] = (self.pid >> 8) as u8;
+        image[0x06] = self.device_release as u8;
+        image[0x07] = (self.device_release >> 8) as u8;
+        image[0x08] = ATTRIBUTES_BUS_POWERED
+            | if self.self_powered {
+                ATTRIBUTES_SELF_POWERED
+            } else {
+                0
+            }
+            | if self.remote_wakeup {
+                ATTRIBUTES_REMOTE_WAKEUP
+            } else {
+                0
+            };
+        // In units of 2 mA
+        image[0x09] = (self.max_power_ma / 2) as u8;
+        image[0x0A] = CONFIG_PULL_DOWN
+            | if self.serial_number.is_empty() {
+                0
+            } else {
+                CONFIG_USE_SERIAL
+            };
+        let mut offset = match self.chip {
+            Chip::Ft245 => {
+                image[0x0A] |= CONFIG_USE_USB_VERSION;
+                image[0x0C] = self.usb_version as u8;
+                image[0x0D] = (self.usb_version >> 8) as u8;
+                STRINGS_START
+            }
+            Chip::Ft2232h => {
+                // Channel types and drivers in 0x00 and 0x01, and the drive strengths in 0x0C and 0x0D, are all 0
+                image[FT2232H_CHIP_TYPE] = CHIP_93C46;
+                FT2232H_STRINGS_START
+            }
+        };
+        offset = write_string(&mut image, 0x0E, offset, self.manufacturer);
+        offset = write_string(&mut image, 0x10, offset, self.product);
+        offset = write_string(&mut image, 0x12, offset, self.serial_number);
+
+        assert!(
+            offset + self.user_area.len() <= CHECKSUM_OFFSET,
+            "strings and user area do not fit in the EEPROM"
+        );
+        let mut i = 0;
+        while i < self.user_area.len() {
+            image[offset + i] = self.user_area[i];
+            i += 1;
+        }
+
+        let checksum = checksum(&image);
+        image[CHECKSUM_OFFSET] = checksum as u8;
+        image[CHECKSUM_OFFSET + 1] = (checksum >> 8) as u8;
+        image
+    }
+}
+
+impl Default for EepromBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Write `s` as a string descriptor at `offset` and point the header entry at `header` to it.
+/// Returns the offset following the descriptor.
+const fn write_string(
+    image: &mut [u8; EEPROM_SIZE],
+    header: usize,
+    offset: usize,
+    s: &str,
+) -> usize {
+    let bytes = s.as_bytes();
+    let mut len = 2;
+    let mut i = 0;
+    while i < bytes.len() {
+        let (c, width) = decode_utf8(bytes, i);
+        i += width;
+        let mut units = [0u16; 2];
+        let count = encode_utf16(c, &mut units);
+        let mut j = 0;
+        while j < count {
+            assert!(
+                offset + len + 2 <= CHECKSUM_OFFSET,
+                "strings do not fit in the EEPROM"
+            );
+            image[offset + len] = units[j] as u8;
+            image[offset + len + 1] = (units[j] >> 8) as u8;
+            len += 2;
+            j += 1;
+        }
+    }
+    assert!(len <= u8::MAX as usize, "string is too long");
+    image[offset] = len as u8;
+    image[offset + 1] = STRING_DESCRIPTOR;
+    image[header] = offset as u8 | STRING_OFFSET_FLAG;
+    image[header + 1] = len as u8;
+    offset + len
+}
+
+/// Decode the character starting at byte `i` of valid UTF-8, returning it with its width in bytes
+const fn decode_utf8(bytes: &[u8], i: usize) -> (u32, usize) {
+    let b = bytes[i] as u32;
+    if b < 0x80 {
+        (b, 1)
+    } else if b < 0xE0 {
+        (((b & 0x1F) << 6) | (bytes[i + 1] as u32 & 0x3F), 2)
+    } else if b < 0xF0 {
+        (
+            ((b & 0x0F) << 12) | ((bytes[i + 1] as u32 & 0x3F) << 6) | (bytes[i + 2] as u32 & 0x3F),
+            3,
+        )
+    } else {
+        (
+            ((b & 0x07) << 18)
+                | ((bytes[i + 1] as u32 & 0x3F) << 12)
+                | ((bytes[i + 2] as u32 & 0x3F) << 6)
+                | (bytes[i + 3] as u32 & 0x3F),
+            4,
+        )
+    }
+}
+
+/// Encode a character as UTF-16, returning the number of code units
+const fn encode_utf16(c: u32, units: &mut [u16; 2]) -> usize {
+    if c < 0x1_0000 {
+        units[0] = c as u16;
+        1
+    } else {
+        let c = c - 0x1_0000;
+        units[0] = 0xD800 | (c >> 10) as u16;
+        units[1] = 0xDC00 | (c & 0x3FF) as u16;
+        2
+    }
+}
+
+/// [FTDI EEPROM checksum](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L3186) over every word but the last
+pub const fn checksum(image: &[u8; EEPROM_SIZE]) -> u16 {
+    let mut checksum = 0xAAAAu16;
+    let mut i = 0;
+    while i < CHECKSUM_OFFSET {
+        checksum ^= image[i] as u16 | ((image[i + 1] as u16) << 8);
+        checksum = checksum.rotate_left(1);
+        i += 2;
+    }
+    checksum
+}
+
+/// Persistent storage for the emulated EEPROM, such as a flash page, so that the host can re-brand the device with stock FTDI tools.
+/// Use [`ReadOnly`] to reject writes from the host, or `()` to keep them in RAM until the next power cycle.
+pub trait EepromStorage {
+    type Error;
+
+    /// Whether the host may write and erase the EEPROM
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    /// Read the committed image into `image`, returning false if nothing has been committed yet
+    fn load(&mut self, image: &mut [u8; EEPROM_SIZE]) -> Result<bool, Self::Error>;
+
+    /// Commit an image written by the host. Only called once its checksum is valid.
+    fn store(&mut self, image: &[u8; EEPROM_SIZE]) -> Result<(), Self::Error>;
+
+    /// Forget the committed image
+    fn erase(&mut self) -> Result<(), Self::Error>;
+}
+
+/// The EEPROM cannot be written or erased by the host.
+pub struct ReadOnly;
+
+impl EepromStorage for ReadOnly {
+    type Error = Infallible;
+
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    fn load(&mut self, _image: &mut [u8; EEPROM_SIZE]) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    fn store(&mut self, _image: &[u8; EEPROM_SIZE]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Writes from the host are kept in RAM only.
+impl EepromStorage for () {
+    type Error = Infallible;
+
+    fn load(&mut self, _image: &mut [u8; EEPROM_SIZE]) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    fn store(&mut self, _image: &[u8; EEPROM_SIZE]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -1,17 +1,58 @@
 #![no_std]
 #![forbid(unsafe_code)]
 
+mod backend;
 mod blaster;
 mod class;
+mod clock;
+mod dap;
+mod delay;
+mod error;
 mod ft245;
+mod mpsse;
+mod pins;
 mod port;
+mod serial_number;
+mod spi;
+#[cfg(feature = "std")]
+pub mod testing;
+mod uart;
+mod xvc;
 
 use usb_device::prelude::UsbVidPid;
 
 extern crate embedded_hal as hal;
+#[cfg(feature = "std")]
+extern crate std;
 
 /// The Vendor ID and Product ID for an Altera Blaster.
 /// Use this when building your USB device for Quartus to recognize the blaster.
 pub const ALTERA_BLASTER_USB_VID_PID: UsbVidPid = UsbVidPid(0x09FB, 0x6001);
 
-pub use blaster::Blaster;
+/// The Vendor ID and Product ID for an FTDI FT2232H.
+/// Use this with a device release of 0x0700 when building your USB device for an [`Mpsse`].
+pub const FTDI_FT2232H_USB_VID_PID: UsbVidPid = UsbVidPid(0x0403, 0x6010);
+
+pub use backend::JtagBackend;
+pub use blaster::{
+    Blaster, Budget, Heartbeat, PollSummary, BLASTER_VEN_REQ_GET_TCK_FREQ, BLASTER_VEN_REQ_RESET,
+    BLASTER_VEN_REQ_SET_TCK_FREQ,
+};
+pub use clock::MillisClock;
+pub use dap::CmsisDap;
+pub use delay::{CycleDelay, TckDelay, UsDelay};
+pub use error::BlasterError;
+pub use ft245::{checksum, EepromBuilder, EepromStorage, ReadOnly, EEPROM_SIZE};
+pub use mpsse::Mpsse;
+pub use pins::{
+    ActiveSerial, ActiveSerialPins, Driven, MaskedWrite, PassiveSerial, PassiveSerialPins, Polarity,
+    ResetDrive, ResetLine, ResetLines, ResetOutput, ResetPins, ReturnClock, ReturnClockPin,
+    TristatePin,
+};
+pub use port::{Mode, Port};
+pub use serial_number::SerialNumber;
+pub use spi::{ShiftSpi, SpiMux, SpiShift};
+pub use uart::{
+    FlowControl, LineCoding, Parity, StopBits, Uart, UartBridge, UartConfig, UartError,
+};
+pub use xvc::{Xvc, XVC_MAX_VECTOR_LEN};
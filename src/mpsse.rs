@@ -0,0 +1,651 @@
+use hal::digital::v2::{InputPin, OutputPin};
+use usb_device::{class_prelude::*, control::RequestType, device::UsbDevice};
+
+use crate::blaster::{would_block, PollSummary};
+use crate::class::{BlasterClass, FTDI_MODEM_STA_DUMMY};
+use crate::delay::TckDelay;
+use crate::error::BlasterError;
+use crate::ft245::FT2232H_ROM;
+use crate::pins::{Driven, Polarity, TristatePin};
+use crate::port::Port;
+
+const MPSSE_WRITE_SIZE: usize = 64;
+const MPSSE_READ_SIZE: usize = 64;
+
+/// [Bit mode](https://github.com/lipro/libftdi/blob/master/src/ftdi.h#L70) that enables the MPSSE
+const BITMODE_MPSSE: u8 = 0x02;
+
+// [Data shifting command](https://www.ftdichip.com/Support/Documents/AppNotes/AN_108_Command_Processor_for_MPSSE_and_MCU_Host_Bus_Emulation_Modes.pdf) flags.
+// Data always changes on the falling edge of TCK and is sampled on the rising edge, as JTAG requires, so the edge flags are ignored.
+/// Clock bits rather than bytes
+const MPSSE_BITMODE: u8 = 0x02;
+/// Shift the least significant bit first
+const MPSSE_LSB: u8 = 0x08;
+/// Write TDI
+const MPSSE_DO_WRITE: u8 = 0x10;
+/// Read TDO
+const MPSSE_DO_READ: u8 = 0x20;
+/// Write TMS, with TDI held at bit 7 of the data
+const MPSSE_WRITE_TMS: u8 = 0x40;
+
+const SET_BITS_LOW: u8 = 0x80;
+const GET_BITS_LOW: u8 = 0x81;
+const SET_BITS_HIGH: u8 = 0x82;
+const GET_BITS_HIGH: u8 = 0x83;
+const LOOPBACK_START: u8 = 0x84;
+const LOOPBACK_END: u8 = 0x85;
+const TCK_DIVISOR: u8 = 0x86;
+const SEND_IMMEDIATE: u8 = 0x87;
+const WAIT_ON_HIGH: u8 = 0x88;
+const WAIT_ON_LOW: u8 = 0x89;
+const DIS_DIV_5: u8 = 0x8A;
+const EN_DIV_5: u8 = 0x8B;
+const EN_3_PHASE: u8 = 0x8C;
+const DIS_3_PHASE: u8 = 0x8D;
+const CLK_BITS: u8 = 0x8E;
+const CLK_BYTES: u8 = 0x8F;
+const CLK_WAIT_ON_HIGH: u8 = 0x94;
+const CLK_WAIT_ON_LOW: u8 = 0x95;
+const EN_ADAPTIVE: u8 = 0x96;
+const DIS_ADAPTIVE: u8 = 0x97;
+const CLK_BYTES_OR_HIGH: u8 = 0x9C;
+const CLK_BYTES_OR_LOW: u8 = 0x9D;
+const DRIVE_OPEN_COLLECTOR: u8 = 0x9E;
+/// Sent back to the host, followed by the opcode, when a command is not recognized
+const BAD_COMMAND: u8 = 0xFA;
+/// The size of an unrecognized command and its reply, see [`command_size`]
+const BAD_COMMAND_SIZE: (usize, usize) = (1, 2);
+
+/// ADBUS pins of the low byte in JTAG mode
+const PIN_TCK: u8 = 0x01;
+const PIN_TDI: u8 = 0x02;
+const PIN_TDO: u8 = 0x04;
+const PIN_TMS: u8 = 0x08;
+
+/// A command that continues over the bytes that follow it
+#[derive(Clone, Copy)]
+enum Pending {
+    /// Bytes left to shift for a data shifting command in byte mode
+    Bytes { opcode: u8, remaining: u32 },
+    /// Bytes of eight TCK pulses left to clock without data
+    Clocks { remaining: u32 },
+}
+
+/// An FT2232H whose channel A emulates the FTDI Multi-Protocol Synchronous Serial Engine (MPSSE) in JTAG mode,
+/// for OpenOCD's `ftdi` driver, openFPGALoader and pyftdi. TCK, TDI, TDO and TMS take the place of ADBUS0 to ADBUS3.
+/// Use [`FTDI_FT2232H_USB_VID_PID`](crate::FTDI_FT2232H_USB_VID_PID) and a device release of 0x0700 when building the USB device.
+pub struct Mpsse<
+    'a,
+    B: UsbBus,
+    E,
+    TDI: TristatePin<Error = E>,
+    TCK: TristatePin<Error = E>,
+    TMS: TristatePin<Error = E>,
+    TDO: InputPin<Error = E>,
+    D: TckDelay = (),
+> {
+    class: BlasterClass<'a, B>,
+    port: Port<E, TDI, TCK, TMS, TDO, (), (), D>,
+    bitmode: u8,
+    pending: Option<Pending>,
+    low_value: u8,
+    low_direction: u8,
+    high_value: u8,
+    high_direction: u8,
+    loopback: bool,
+    divisor: u16,
+    divide_by_5: bool,
+    send_buffer: [u8; MPSSE_WRITE_SIZE],
+    send_len: usize,
+    recv_buffer: [u8; MPSSE_READ_SIZE],
+    recv_len: usize,
+    /// The first error raised while handling a USB reset or control request
+    error: Option<BlasterError<E>>,
+}
+
+impl<
+        'a,
+        B: UsbBus,
+        E,
+        TDI: OutputPin<Error = E>,
+        TCK: OutputPin<Error = E>,
+        TMS: OutputPin<Error = E>,
+        TDO: InputPin<Error = E>,
+    > Mpsse<'a, B, E, Driven<TDI>, Driven<TCK>, Driven<TMS>, TDO>
+{
+    /// Allocate an MPSSE on the USB bus. Takes control of the four JTAG pins.
+    /// TDI, TCK and TMS are always driven; use [`Mpsse::new_tristate`] if they are shared with another device.
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        tdi: TDI,
+        tck: TCK,
+        tms: TMS,
+        tdo: TDO,
+    ) -> Mpsse<'a, B, E, Driven<TDI>, Driven<TCK>, Driven<TMS>, TDO> {
+        Mpsse::new_tristate(alloc, Driven(tdi), Driven(tck), Driven(tms), tdo)
+    }
+}
+
+impl<
+        'a,
+        B: UsbBus,
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+    > Mpsse<'a, B, E, TDI, TCK, TMS, TDO>
+{
+    /// Allocate an MPSSE on the USB bus. Takes control of the four JTAG pins.
+    /// TDI, TCK and TMS are released to high impedance whenever the host makes TCK an input.
+    pub fn new_tristate(
+        alloc: &'a UsbBusAllocator<B>,
+        tdi: TDI,
+        tck: TCK,
+        tms: TMS,
+        tdo: TDO,
+    ) -> Mpsse<'a, B, E, TDI, TCK, TMS, TDO> {
+        let mut mpsse = Mpsse {
+            class: BlasterClass::new(alloc, MPSSE_WRITE_SIZE as u16, MPSSE_READ_SIZE as u16),
+            port: Port::new(tdi, tck, tms, tdo),
+            bitmode: 0,
+            pending: None,
+            low_value: 0,
+            low_direction: 0,
+            high_value: 0,
+            high_direction: 0,
+            loopback: false,
+            divisor: 0,
+            divide_by_5: true,
+            send_buffer: [0u8; MPSSE_WRITE_SIZE],
+            send_len: 0,
+            recv_buffer: [0u8; MPSSE_READ_SIZE],
+            recv_len: 0,
+            error: None,
+        };
+        mpsse.class.set_eeprom(FT2232H_ROM);
+        mpsse.update_tck_frequency();
+        mpsse.send_buffer[0] = FTDI_MODEM_STA_DUMMY[0];
+        mpsse.send_buffer[1] = FTDI_MODEM_STA_DUMMY[1];
+        mpsse
+    }
+}
+
+impl<
+        'a,
+        B: UsbBus,
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+        D: TckDelay,
+    > Mpsse<'a, B, E, TDI, TCK, TMS, TDO, D>
+{
+    /// Attach a delay, so that TCK stays under the frequency set by the host with the clock divisor.
+    pub fn with_delay<D2: TckDelay>(self, delay: D2) -> Mpsse<'a, B, E, TDI, TCK, TMS, TDO, D2> {
+        self.map_port(|port| port.with_delay(delay))
+    }
+
+    /// Mark the lines that pass through inverting buffers. The JTAG state is tracked on the logic levels.
+    pub fn with_polarity(mut self, polarity: Polarity) -> Self {
+        self.port.set_polarity(polarity);
+        self
+    }
+
+    /// Swap the port for one built from it, keeping everything else
+    fn map_port<D2: TckDelay>(
+        self,
+        f: impl FnOnce(
+            Port<E, TDI, TCK, TMS, TDO, (), (), D>,
+        ) -> Port<E, TDI, TCK, TMS, TDO, (), (), D2>,
+    ) -> Mpsse<'a, B, E, TDI, TCK, TMS, TDO, D2> {
+        Mpsse {
+            class: self.class,
+            port: f(self.port),
+            bitmode: self.bitmode,
+            pending: self.pending,
+            low_value: self.low_value,
+            low_direction: self.low_direction,
+            high_value: self.high_value,
+            high_direction: self.high_direction,
+            loopback: self.loopback,
+            divisor: self.divisor,
+            divide_by_5: self.divide_by_5,
+            send_buffer: self.send_buffer,
+            send_len: self.send_len,
+            recv_buffer: self.recv_buffer,
+            recv_len: self.recv_len,
+            error: self.error,
+        }
+    }
+
+    /// The TCK frequency set by the host with the clock divisor, in Hz
+    pub fn tck_frequency(&self) -> u32 {
+        let base = if self.divide_by_5 {
+            12_000_000
+        } else {
+            60_000_000
+        };
+        base / ((1 + self.divisor as u32) * 2)
+    }
+
+    /// Read data from the host output endpoint into the internal read buffer.
+    pub fn read(&mut self) -> Result<usize, BlasterError<E>> {
+        if self.recv_len == self.recv_buffer.len() {
+            return Err(UsbError::WouldBlock.into());
+        }
+        let amount = self.class.read(&mut self.recv_buffer[self.recv_len..])?;
+        self.recv_len += amount;
+        Ok(amount)
+    }
+
+    /// Write the modem status and any replies to the host input endpoint.
+    pub fn write(&mut self) -> Result<usize, BlasterError<E>> {
+        let amount = self.class.write(&self.send_buffer[..self.send_len + 2])?;
+        let data_sent = amount.saturating_sub(2);
+        self.send_buffer
+            .copy_within((2 + data_sent)..(self.send_len + 2), 2);
+        self.send_len -= data_sent;
+        Ok(amount)
+    }
+
+    /// Runs the MPSSE commands in the internal read buffer until either no complete command is left or the internal write buffer is full.
+    /// Data is discarded unless the host has enabled the MPSSE bit mode.
+    pub fn handle(&mut self) -> Result<(), BlasterError<E>> {
+        if self.bitmode != BITMODE_MPSSE {
+            self.recv_len = 0;
+            return Ok(());
+        }
+        let mut consumed = 0;
+        let res = self.run(&mut consumed);
+        self.recv_buffer.copy_within(consumed..self.recv_len, 0);
+        self.recv_len -= consumed;
+        res
+    }
+
+    /// Poll the USB device, read data from the host, handle it and write the replies.
+    /// Use [`Mpsse::read`], [`Mpsse::handle`] and [`Mpsse::write`] instead if the USB device has other classes to poll.
+    pub fn poll(&mut self, usb_dev: &mut UsbDevice<'a, B>) -> Result<PollSummary, BlasterError<E>> {
+        usb_dev.poll(&mut [self]);
+        let mut summary = PollSummary {
+            received: would_block(self.read())?,
+            ..PollSummary::default()
+        };
+        let before = self.recv_len;
+        self.handle()?;
+        summary.handled = before - self.recv_len;
+        summary.sent = would_block(self.write())?;
+        summary.pending = self.recv_len;
+        Ok(summary)
+    }
+
+    /// Take the first error raised while handling a USB reset or control request.
+    pub fn take_error(&mut self) -> Option<BlasterError<E>> {
+        self.error.take()
+    }
+
+    /// Keep the first error until the application takes it
+    fn store_error(&mut self, error: BlasterError<E>) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+
+    /// Run commands, counting the bytes used in `consumed`
+    fn run(&mut self, consumed: &mut usize) -> Result<(), BlasterError<E>> {
+        loop {
+            let available = self.recv_len - *consumed;
+            let space = self.send_buffer.len() - 2 - self.send_len;
+            match self.pending {
+                Some(Pending::Bytes { opcode, remaining }) => {
+                    let write = opcode & MPSSE_DO_WRITE != 0;
+                    let read = opcode & MPSSE_DO_READ != 0;
+                    if (write && available == 0) || (read && space == 0) {
+                        return Ok(());
+                    }
+                    let data = if write {
+                        *consumed += 1;
+                        self.recv_buffer[*consumed - 1]
+                    } else {
+                        0
+                    };
+                    let tdo = self.shift_bits(opcode, data, 8)?;
+                    if read {
+                        self.push(tdo);
+                    }
+                    self.pending = if remaining > 1 {
+                        Some(Pending::Bytes {
+                            opcode,
+                            remaining: remaining - 1,
+                        })
+                    } else {
+                        None
+                    };
+                    continue;
+                }
+                Some(Pending::Clocks { remaining }) => {
+                    self.clock_idle(8)?;
+                    self.pending = if remaining > 1 {
+                        Some(Pending::Clocks {
+                            remaining: remaining - 1,
+                        })
+                    } else {
+                        None
+                    };
+                    continue;
+                }
+                None => {}
+            }
+            if available == 0 {
+                return Ok(());
+            }
+            let opcode = self.recv_buffer[*consumed];
+            let (len, replies) = command_size(opcode);
+            if available < len || space < replies {
+                return Ok(());
+            }
+            let arg = |n: usize| {
+                if n < len {
+                    self.recv_buffer[*consumed + n]
+                } else {
+                    0
+                }
+            };
+            let (arg1, arg2) = (arg(1), arg(2));
+            *consumed += len;
+            self.execute(opcode, arg1, arg2)?;
+        }
+    }
+
+    /// Run one complete command, whose arguments are `arg1` and `arg2`
+    fn execute(&mut self, opcode: u8, arg1: u8, arg2: u8) -> Result<(), BlasterError<E>> {
+        let length = arg1 as u32 | (arg2 as u32) << 8;
+        match opcode {
+            _ if command_size(opcode) == BAD_COMMAND_SIZE => self.bad_command(opcode),
+            _ if opcode & MPSSE_WRITE_TMS != 0 => {
+                let tdi = arg2 & 0x80 != 0;
+                let mut tdo = 0u8;
+                for n in 0..(arg1 & 0x7) + 1 {
+                    let bit = self.clock(Some(arg2 >> n & 1 != 0), tdi)?;
+                    tdo = (tdo >> 1) | (bit as u8) << 7;
+                }
+                if opcode & MPSSE_DO_READ != 0 {
+                    self.push(tdo);
+                }
+            }
+            _ if opcode & 0x80 == 0 && opcode & MPSSE_BITMODE != 0 => {
+                let tdo = self.shift_bits(opcode, arg2, (arg1 & 0x7) + 1)?;
+                if opcode & MPSSE_DO_READ != 0 {
+                    self.push(tdo);
+                }
+            }
+            _ if opcode & 0x80 == 0 => {
+                self.pending = Some(Pending::Bytes {
+                    opcode,
+                    remaining: length + 1,
+                });
+            }
+            SET_BITS_LOW => {
+                self.low_value = arg1;
+                self.low_direction = arg2;
+                self.port
+                    .set_output_enable(self.low_direction & PIN_TCK != 0)
+                    .map_err(BlasterError::Pin)?;
+                self.port.drive(
+                    arg1 & PIN_TCK != 0,
+                    arg1 & PIN_TDI != 0,
+                    arg1 & PIN_TMS != 0,
+                )?;
+            }
+            GET_BITS_LOW => {
+                let tdo = if self.port.tdo().map_err(BlasterError::Pin)? {
+                    PIN_TDO
+                } else {
+                    0
+                };
+                let value = (self.low_value & self.low_direction & !PIN_TDO) | tdo;
+                self.push(value);
+            }
+            SET_BITS_HIGH => {
+                self.high_value = arg1;
+                self.high_direction = arg2;
+            }
+            GET_BITS_HIGH => {
+                let value = self.high_value & self.high_direction;
+                self.push(value);
+            }
+            LOOPBACK_START => self.loopback = true,
+            LOOPBACK_END => self.loopback = false,
+            TCK_DIVISOR => {
+                self.divisor = length as u16;
+                self.update_tck_frequency();
+            }
+            DIS_DIV_5 => {
+                self.divide_by_5 = false;
+                self.update_tck_frequency();
+            }
+            EN_DIV_5 => {
+                self.divide_by_5 = true;
+                self.update_tck_frequency();
+            }
+            CLK_BITS => self.clock_idle((arg1 & 0x7) + 1)?,
+            CLK_BYTES | CLK_BYTES_OR_HIGH | CLK_BYTES_OR_LOW => {
+                self.pending = Some(Pending::Clocks {
+                    remaining: length + 1,
+                });
+            }
+            // Replies are flushed on every write, there are no GPIOL pins to wait on and TCK is not adaptive
+            SEND_IMMEDIATE | WAIT_ON_HIGH | WAIT_ON_LOW | EN_3_PHASE | DIS_3_PHASE
+            | CLK_WAIT_ON_HIGH | CLK_WAIT_ON_LOW | EN_ADAPTIVE | DIS_ADAPTIVE
+            | DRIVE_OPEN_COLLECTOR => {}
+            _ => self.bad_command(opcode),
+        }
+        Ok(())
+    }
+
+    /// Shift `bits` bits of `data` out on TDI, returning the bits read from TDO packed the way the FT2232H does
+    fn shift_bits(&mut self, opcode: u8, data: u8, bits: u8) -> Result<u8, BlasterError<E>> {
+        let lsb_first = opcode & MPSSE_LSB != 0;
+        let mut tdo = 0u8;
+        for n in 0..bits {
+            if lsb_first {
+                let bit = self.clock(None, data >> n & 1 != 0)?;
+                tdo = (tdo >> 1) | (bit as u8) << 7;
+            } else {
+                let bit = self.clock(None, data >> (7 - n) & 1 != 0)?;
+                tdo = (tdo << 1) | bit as u8;
+            }
+        }
+        Ok(tdo)
+    }
+
+    /// Pulse TCK without changing TDI or TMS
+    fn clock_idle(&mut self, bits: u8) -> Result<(), BlasterError<E>> {
+        let tdi = self.low_value & PIN_TDI != 0;
+        for _ in 0..bits {
+            self.clock(None, tdi)?;
+        }
+        Ok(())
+    }
+
+    /// Pulse TCK once, driving TMS if given. Returns TDO, or TDI in loopback.
+    fn clock(&mut self, tms: Option<bool>, tdi: bool) -> Result<bool, BlasterError<E>> {
+        let tdo = match tms {
+            Some(tms) => {
+                set_bit(&mut self.low_value, PIN_TMS, tms);
+                self.port.clock_tms(tms, tdi)?
+            }
+            None => self.port.clock_tdi(tdi)?,
+        };
+        set_bit(&mut self.low_value, PIN_TDI, tdi);
+        Ok(if self.loopback { tdi } else { tdo })
+    }
+
+    /// Limit TCK to the frequency the divisor selects, once a delay is attached
+    fn update_tck_frequency(&mut self) {
+        let hz = self.tck_frequency();
+        self.port.set_tck_frequency(hz);
+    }
+
+    /// Reply to an opcode the engine does not know, as the FT2232H does
+    fn bad_command(&mut self, opcode: u8) {
+        self.push(BAD_COMMAND);
+        self.push(opcode);
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.send_buffer[2 + self.send_len] = byte;
+        self.send_len += 1;
+    }
+
+    /// Forget any command in progress
+    fn reset_engine(&mut self) {
+        self.pending = None;
+        self.recv_len = 0;
+        self.send_len = 0;
+    }
+}
+
+impl<
+        B,
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+        D: TckDelay,
+    > UsbClass<B> for Mpsse<'_, B, E, TDI, TCK, TMS, TDO, D>
+where
+    B: UsbBus,
+{
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        self.class.get_configuration_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.class.reset();
+        if let Err(err) = self.port.reset() {
+            self.store_error(BlasterError::Pin(err));
+        }
+        self.bitmode = 0;
+        self.loopback = false;
+        self.divisor = 0;
+        self.divide_by_5 = true;
+        self.update_tck_frequency();
+        self.reset_engine();
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if let Err(err) = self.class.control_in(xfer) {
+            self.store_error(err.into());
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        /// See [Linux kernel ftdi_sio.h](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L74)
+        const FTDI_VEN_REQ_RESET: u8 = 0x00;
+        /// [Set latency timer](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L324)
+        const FTDI_VEN_REQ_SET_LAT_TIMER: u8 = 0x09;
+        /// [Set bitmode](https://github.com/lipro/libftdi/blob/master/src/ftdi.c#L1921)
+        const FTDI_VEN_REQ_SET_BITMODE: u8 = 0x0B;
+
+        let req = *xfer.request();
+        if req.request_type != RequestType::Vendor {
+            return;
+        }
+        let accepted = match req.request {
+            FTDI_VEN_REQ_RESET => {
+                const RESET_SIO: u16 = 0x0000;
+                const RESET_PURGE_RX: u16 = 0x0001;
+                const RESET_PURGE_TX: u16 = 0x0002;
+                match req.value {
+                    RESET_SIO => {
+                        self.reset_engine();
+                        true
+                    }
+                    RESET_PURGE_RX => {
+                        self.pending = None;
+                        self.recv_len = 0;
+                        true
+                    }
+                    RESET_PURGE_TX => {
+                        self.send_len = 0;
+                        true
+                    }
+                    _ => {
+                        self.store_error(BlasterError::InvalidRequest(req.request));
+                        false
+                    }
+                }
+            }
+            FTDI_VEN_REQ_SET_LAT_TIMER => match req.value {
+                1..=255 => {
+                    self.class.set_latency_timer(req.value as u8);
+                    true
+                }
+                _ => {
+                    self.store_error(BlasterError::InvalidRequest(req.request));
+                    false
+                }
+            },
+            FTDI_VEN_REQ_SET_BITMODE => {
+                // The mode is in the upper byte, the pin directions for bit-bang modes in the lower byte
+                self.bitmode = (req.value >> 8) as u8;
+                self.reset_engine();
+                true
+            }
+            _ => true,
+        };
+        let res = if accepted {
+            xfer.accept()
+        } else {
+            xfer.reject()
+        };
+        if let Err(err) = res {
+            self.store_error(err.into());
+        }
+    }
+}
+
+/// The number of bytes in a command including its opcode, and the most replies it sends.
+/// Data shifted in byte mode follows the command and is not counted.
+fn command_size(opcode: u8) -> (usize, usize) {
+    if opcode & 0x80 == 0 {
+        let read = (opcode & MPSSE_DO_READ != 0) as usize;
+        return if opcode & MPSSE_WRITE_TMS != 0 {
+            if opcode & MPSSE_BITMODE == 0 || opcode & MPSSE_DO_WRITE != 0 {
+                BAD_COMMAND_SIZE
+            } else {
+                (3, read)
+            }
+        } else if opcode & (MPSSE_DO_WRITE | MPSSE_DO_READ) == 0 {
+            BAD_COMMAND_SIZE
+        } else if opcode & MPSSE_BITMODE != 0 {
+            (2 + (opcode & MPSSE_DO_WRITE != 0) as usize, read)
+        } else {
+            (3, 0)
+        };
+    }
+    match opcode {
+        SET_BITS_LOW | SET_BITS_HIGH | TCK_DIVISOR | CLK_BYTES | CLK_BYTES_OR_HIGH
+        | CLK_BYTES_OR_LOW | DRIVE_OPEN_COLLECTOR => (3, 0),
+        GET_BITS_LOW | GET_BITS_HIGH => (1, 1),
+        CLK_BITS => (2, 0),
+        LOOPBACK_START | LOOPBACK_END | SEND_IMMEDIATE | WAIT_ON_HIGH | WAIT_ON_LOW | DIS_DIV_5
+        | EN_DIV_5 | EN_3_PHASE | DIS_3_PHASE | CLK_WAIT_ON_HIGH | CLK_WAIT_ON_LOW
+        | EN_ADAPTIVE | DIS_ADAPTIVE => (1, 0),
+        _ => BAD_COMMAND_SIZE,
+    }
+}
+
+fn set_bit(byte: &mut u8, mask: u8, high: bool) {
+    if high {
+        *byte |= mask;
+    } else {
+        *byte &= !mask;
+    }
+}
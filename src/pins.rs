@@ -0,0 +1,297 @@
+use hal::digital::v2::{InputPin, OutputPin};
+
+/// An output pin that can be released to high impedance and driven again at runtime.
+/// The blaster floats TDI, TCK and TMS whenever the host clears the output enable (OE) bit.
+pub trait TristatePin: OutputPin {
+    /// Stop driving the line, leaving it at high impedance
+    fn set_floating(&mut self) -> Result<(), Self::Error>;
+    /// Drive the line again at the level last set
+    fn set_driven(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Marks the lines that pass through an inverting buffer, such as an inverting level shifter or an open-collector transistor.
+/// The host keeps working with logic levels; only the levels on the pins are inverted.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct Polarity {
+    pub tdi: bool,
+    pub tck: bool,
+    pub tms: bool,
+    pub tdo: bool,
+    /// Active Serial (AS) chip enable
+    pub nce: bool,
+    /// Active Serial (AS) chip select
+    pub ncs: bool,
+    /// Active Serial (AS) data out
+    pub dataout: bool,
+    /// Passive Serial (PS) configuration status
+    pub nstatus: bool,
+    /// Passive Serial (PS) configuration done
+    pub conf_done: bool,
+    /// Return clock for adaptive clocking
+    pub rtck: bool,
+}
+
+/// An output pin that is always driven, regardless of the output enable (OE) bit.
+pub struct Driven<P>(pub P);
+
+impl<P: OutputPin> OutputPin for Driven<P> {
+    type Error = P::Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.set_high()
+    }
+}
+
+impl<P: OutputPin> TristatePin for Driven<P> {
+    fn set_floating(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_driven(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Optional pins for Active Serial (AS) programming of a serial configuration device (EPCS/EPCQ).
+/// Use `()` if the board does not route these signals.
+pub trait ActiveSerialPins<E> {
+    /// Drive the active-low chip enable (nCE) line
+    fn set_nce(&mut self, high: bool) -> Result<(), E>;
+    /// Drive the active-low chip select (nCS) line
+    fn set_ncs(&mut self, high: bool) -> Result<(), E>;
+    /// Sample the active serial data out (DATAOUT) line, or `None` if it is not connected
+    fn dataout(&self) -> Result<Option<bool>, E>;
+    /// Whether the DATAOUT line is connected at all
+    fn is_dataout_connected(&self) -> bool {
+        true
+    }
+}
+
+impl<E> ActiveSerialPins<E> for () {
+    fn set_nce(&mut self, _high: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn set_ncs(&mut self, _high: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn dataout(&self) -> Result<Option<bool>, E> {
+        Ok(None)
+    }
+
+    fn is_dataout_connected(&self) -> bool {
+        false
+    }
+}
+
+/// The three Active Serial (AS) pins of a USB Blaster header.
+pub struct ActiveSerial<NCE, NCS, DATAOUT> {
+    pub nce: NCE,
+    pub ncs: NCS,
+    pub dataout: DATAOUT,
+}
+
+impl<E, NCE, NCS, DATAOUT> ActiveSerialPins<E> for ActiveSerial<NCE, NCS, DATAOUT>
+where
+    NCE: OutputPin<Error = E>,
+    NCS: OutputPin<Error = E>,
+    DATAOUT: InputPin<Error = E>,
+{
+    fn set_nce(&mut self, high: bool) -> Result<(), E> {
+        if high {
+            self.nce.set_high()
+        } else {
+            self.nce.set_low()
+        }
+    }
+
+    fn set_ncs(&mut self, high: bool) -> Result<(), E> {
+        if high {
+            self.ncs.set_high()
+        } else {
+            self.ncs.set_low()
+        }
+    }
+
+    fn dataout(&self) -> Result<Option<bool>, E> {
+        self.dataout.is_high().map(Some)
+    }
+}
+
+/// Optional status inputs for Passive Serial (PS) configuration.
+/// Use `()` if the board does not route these signals.
+pub trait PassiveSerialPins<E> {
+    /// Sample the active-low configuration status (nSTATUS) line, or `None` if it is not connected
+    fn nstatus(&self) -> Result<Option<bool>, E>;
+    /// Sample the configuration done (CONF_DONE) line, or `None` if it is not connected
+    fn conf_done(&self) -> Result<Option<bool>, E>;
+}
+
+impl<E> PassiveSerialPins<E> for () {
+    fn nstatus(&self) -> Result<Option<bool>, E> {
+        Ok(None)
+    }
+
+    fn conf_done(&self) -> Result<Option<bool>, E> {
+        Ok(None)
+    }
+}
+
+/// The two Passive Serial (PS) status pins of a USB Blaster header.
+/// DCLK, DATA0 and nCONFIG are driven on the TCK, TDI and TMS pins respectively.
+pub struct PassiveSerial<NSTATUS, CONFDONE> {
+    pub nstatus: NSTATUS,
+    pub conf_done: CONFDONE,
+}
+
+impl<E, NSTATUS, CONFDONE> PassiveSerialPins<E> for PassiveSerial<NSTATUS, CONFDONE>
+where
+    NSTATUS: InputPin<Error = E>,
+    CONFDONE: InputPin<Error = E>,
+{
+    fn nstatus(&self) -> Result<Option<bool>, E> {
+        self.nstatus.is_high().map(Some)
+    }
+
+    fn conf_done(&self) -> Result<Option<bool>, E> {
+        self.conf_done.is_high().map(Some)
+    }
+}
+
+/// Writes TDI, TCK and TMS at once, for boards where they sit on one GPIO port that can be set with a single masked register write.
+/// This saves a read-modify-write per pin on every edge. Use `()` to set the pins one by one.
+pub trait MaskedWrite<E> {
+    /// Whether the lines can be written at once
+    fn is_available(&self) -> bool {
+        true
+    }
+    /// Drive the three lines to the given physical levels in one write
+    fn write_lines(&mut self, tdi: bool, tck: bool, tms: bool) -> Result<(), E>;
+}
+
+impl<E> MaskedWrite<E> for () {
+    fn is_available(&self) -> bool {
+        false
+    }
+
+    fn write_lines(&mut self, _tdi: bool, _tck: bool, _tms: bool) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+/// Optional reset outputs for the targets on the JTAG chain.
+/// Use `()` if the board does not route these signals.
+pub trait ResetPins<E> {
+    /// Assert or release the active-low TAP reset (nTRST) line, returning false if it is not connected
+    fn set_ntrst(&mut self, asserted: bool) -> Result<bool, E>;
+    /// Assert or release the active-low system reset (nSRST) line, returning false if it is not connected
+    fn set_nsrst(&mut self, asserted: bool) -> Result<bool, E>;
+}
+
+impl<E> ResetPins<E> for () {
+    fn set_ntrst(&mut self, _asserted: bool) -> Result<bool, E> {
+        Ok(false)
+    }
+
+    fn set_nsrst(&mut self, _asserted: bool) -> Result<bool, E> {
+        Ok(false)
+    }
+}
+
+/// One active-low reset output. Use `()` if the line is not connected.
+pub trait ResetOutput<E> {
+    /// Assert or release the line, returning false if it is not connected
+    fn set_asserted(&mut self, asserted: bool) -> Result<bool, E>;
+}
+
+impl<E> ResetOutput<E> for () {
+    fn set_asserted(&mut self, _asserted: bool) -> Result<bool, E> {
+        Ok(false)
+    }
+}
+
+/// How a reset line is released
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ResetDrive {
+    /// Driven high
+    PushPull,
+    /// Left at high impedance for a pull-up, so that other reset sources can pull the line low too
+    OpenDrain,
+}
+
+/// A reset output pin. It is driven low to assert the reset either way.
+/// Open-drain release floats the pin, so wrap a plain output pin in [`Driven`] only with [`ResetDrive::PushPull`].
+pub struct ResetLine<P> {
+    pub pin: P,
+    pub drive: ResetDrive,
+}
+
+impl<E, P: TristatePin<Error = E>> ResetOutput<E> for ResetLine<P> {
+    fn set_asserted(&mut self, asserted: bool) -> Result<bool, E> {
+        if asserted {
+            self.pin.set_low()?;
+            self.pin.set_driven()?;
+        } else if self.drive == ResetDrive::PushPull {
+            self.pin.set_high()?;
+            self.pin.set_driven()?;
+        } else {
+            self.pin.set_floating()?;
+        }
+        Ok(true)
+    }
+}
+
+/// The nTRST and nSRST lines of a JTAG header, either of which can be `()`.
+pub struct ResetLines<NTRST, NSRST> {
+    pub ntrst: NTRST,
+    pub nsrst: NSRST,
+}
+
+impl<E, NTRST, NSRST> ResetPins<E> for ResetLines<NTRST, NSRST>
+where
+    NTRST: ResetOutput<E>,
+    NSRST: ResetOutput<E>,
+{
+    fn set_ntrst(&mut self, asserted: bool) -> Result<bool, E> {
+        self.ntrst.set_asserted(asserted)
+    }
+
+    fn set_nsrst(&mut self, asserted: bool) -> Result<bool, E> {
+        self.nsrst.set_asserted(asserted)
+    }
+}
+
+/// An optional return clock (RTCK) input for adaptive clocking, as ARM cores with a synchronised TCK drive it.
+/// Use `()` if the board does not route this signal.
+pub trait ReturnClock<E> {
+    /// Sample the RTCK line, or `None` if it is not connected
+    fn rtck(&self) -> Result<Option<bool>, E>;
+    /// Whether the RTCK line is connected at all
+    fn is_connected(&self) -> bool {
+        true
+    }
+}
+
+impl<E> ReturnClock<E> for () {
+    fn rtck(&self) -> Result<Option<bool>, E> {
+        Ok(None)
+    }
+
+    fn is_connected(&self) -> bool {
+        false
+    }
+}
+
+/// An RTCK input pin. TCK edges wait for it to follow.
+pub struct ReturnClockPin<P>(pub P);
+
+impl<E, P: InputPin<Error = E>> ReturnClock<E> for ReturnClockPin<P> {
+    fn rtck(&self) -> Result<Option<bool>, E> {
+        self.0.is_high().map(Some)
+    }
+}
@@ -1,22 +1,80 @@
 use hal::digital::v2::{InputPin, OutputPin};
 
+use crate::backend::JtagBackend;
+use crate::delay::TckDelay;
+use crate::error::BlasterError;
+use crate::pins::{
+    ActiveSerialPins, MaskedWrite, PassiveSerialPins, Polarity, ResetPins, ReturnClock, TristatePin,
+};
+use crate::spi::ShiftSpi;
+
+/// The configuration scheme the blaster lines are used for
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Mode {
+    /// TCK, TDI and TMS drive the JTAG chain, TDO is read back
+    Jtag,
+    /// TCK, TDI and TMS drive DCLK, DATA0 and nCONFIG; CONF_DONE and nSTATUS are read back
+    PassiveSerial,
+}
+
+/// The default [`JtagBackend`], which bit-bangs the lines on GPIO pins.
+/// Optional parts are attached with the `with_*` builders, which [`Blaster`](crate::Blaster) also provides.
 pub struct Port<
     E,
-    TDI: OutputPin<Error = E>,
-    TCK: OutputPin<Error = E>,
-    TMS: OutputPin<Error = E>,
+    TDI: TristatePin<Error = E>,
+    TCK: TristatePin<Error = E>,
+    TMS: TristatePin<Error = E>,
     TDO: InputPin<Error = E>,
+    AS: ActiveSerialPins<E> = (),
+    PS: PassiveSerialPins<E> = (),
+    D: TckDelay = (),
+    RT: ReturnClock<E> = (),
+    RS: ResetPins<E> = (),
+    W: MaskedWrite<E> = (),
+    SP: ShiftSpi<E> = (),
 > {
     tdi: TDI,
     tck: TCK,
     tms: TMS,
     tdo: TDO,
+    as_pins: AS,
+    /// nCS was driven low, so shift-mode reads sample DATAOUT instead of TDO
+    as_selected: bool,
+    ps_pins: PS,
+    mode: Mode,
+    /// TDI, TCK and TMS are driven rather than floating
+    output_enabled: bool,
     jtag_state: JTAGState,
-    shift_count: u8,
-    read_en: bool,
     got_clock: bool,
+    /// The level TMS was last driven to, which is held while data is clocked
+    tms_high: bool,
+    /// Lines behind inverting buffers
+    polarity: Polarity,
+    delay: D,
+    /// TCK frequency limit in Hz, or 0 for none
+    tck_frequency: u32,
+    /// Minimum time TCK is held high or low, in nanoseconds
+    half_period_ns: u32,
+    rtck: RT,
+    /// How many times RTCK is sampled after a TCK edge before giving up
+    rtck_timeout: u32,
+    reset_pins: RS,
+    /// How long nTRST is held low when pulsed, in microseconds
+    ntrst_pulse_us: u32,
+    /// How long nSRST is held low when pulsed, in microseconds
+    nsrst_pulse_us: u32,
+    masked: W,
+    spi: SP,
+    /// TDI and TCK are handed over to the SPI peripheral
+    spi_attached: bool,
 }
 
+/// How long reset lines are held low when pulsed until [`Port::set_reset_pulse`] is called, in microseconds
+const RESET_PULSE_US_DEFAULT: u32 = 1000;
+
+/// RTCK samples taken after each TCK edge until [`Port::set_rtck_timeout`] is called
+const RTCK_TIMEOUT_DEFAULT: u32 = 10_000;
+
 #[derive(PartialEq, Clone)]
 #[repr(u8)]
 enum JTAGState {
@@ -99,34 +157,73 @@ impl Default for JTAGState {
 
 impl<
         E,
-        TDI: OutputPin<Error = E>,
-        TCK: OutputPin<Error = E>,
-        TMS: OutputPin<Error = E>,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
         TDO: InputPin<Error = E>,
     > Port<E, TDI, TCK, TMS, TDO>
+{
+    /// Take control of the four JTAG pins
+    pub fn new(tdi: TDI, tck: TCK, tms: TMS, tdo: TDO) -> Port<E, TDI, TCK, TMS, TDO> {
+        Port {
+            tdi,
+            tck,
+            tms,
+            tdo,
+            as_pins: (),
+            as_selected: false,
+            ps_pins: (),
+            mode: Mode::Jtag,
+            output_enabled: true,
+            jtag_state: JTAGState::Reset,
+            got_clock: false,
+            tms_high: false,
+            polarity: Polarity::default(),
+            delay: (),
+            tck_frequency: 0,
+            half_period_ns: 0,
+            rtck: (),
+            rtck_timeout: RTCK_TIMEOUT_DEFAULT,
+            reset_pins: (),
+            ntrst_pulse_us: RESET_PULSE_US_DEFAULT,
+            nsrst_pulse_us: RESET_PULSE_US_DEFAULT,
+            masked: (),
+            spi: (),
+            spi_attached: false,
+        }
+    }
+}
+
+impl<
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+        AS: ActiveSerialPins<E>,
+        PS: PassiveSerialPins<E>,
+        D: TckDelay,
+        RT: ReturnClock<E>,
+        RS: ResetPins<E>,
+        W: MaskedWrite<E>,
+        SP: ShiftSpi<E>,
+    > Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT, RS, W, SP>
 where
     TDI: OutputPin,
     TCK: OutputPin,
     TMS: OutputPin,
     TDO: InputPin,
 {
-    /// [Shift bit](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L173)
-    const BLASTER_STA_SHIFT: u8 = 0x80;
-    /// [Read bit](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L171)
-    const BLASTER_STA_READ: u8 = 0x40;
-    /// [Byte shift count mask](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L174)
-    const BLASTER_STA_CNT_MASK: u8 = 0x3f;
-
     /// [Output enable](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L182)
-    const _BLASTER_STA_OUT_OE: u8 = 0x20;
+    const BLASTER_STA_OUT_OE: u8 = 0x20;
     /// [TDI high bit](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L181)
     const BLASTER_STA_OUT_TDI: u8 = 0x10;
     /// [nCS high bit](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L180)
-    /// Always 0, which means chip is selected
-    const _BLASTER_STA_OUT_NCS: u8 = 0x08;
+    /// Low selects the serial configuration device in AS mode
+    const BLASTER_STA_OUT_NCS: u8 = 0x08;
     /// [nCE high bit](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L179)
-    /// Always 0, which means chip is enabled
-    const _BLASTER_STA_OUT_NCE: u8 = 0x04;
+    /// High keeps the FPGA off the AS lines while the host programs the configuration device
+    const BLASTER_STA_OUT_NCE: u8 = 0x04;
     /// [TMS high bit](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L178)
     const BLASTER_STA_OUT_TMS: u8 = 0x02;
     /// [TCK high bit](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L177)
@@ -135,122 +232,348 @@ where
     // Data from device
     const BLASTER_STA_IN_TDO: u8 = 0x01;
     /// Active serial data out (not used for JTAG)
-    const _BLASTER_STA_IN_DATAOUT: u8 = 0x02;
+    const BLASTER_STA_IN_DATAOUT: u8 = 0x02;
+    /// Configuration done, in place of TDO in PS mode
+    const BLASTER_STA_IN_CONF_DONE: u8 = 0x01;
+    /// Active-low configuration status, in place of DATAOUT in PS mode
+    const BLASTER_STA_IN_NSTATUS: u8 = 0x02;
 
-    pub fn new(tdi: TDI, tck: TCK, tms: TMS, tdo: TDO) -> Port<E, TDI, TCK, TMS, TDO> {
+    /// Replace the optional parts, given and returned in the order of the type parameters, keeping the pins and the state
+    fn map_parts<
+        AS2: ActiveSerialPins<E>,
+        PS2: PassiveSerialPins<E>,
+        D2: TckDelay,
+        RT2: ReturnClock<E>,
+        RS2: ResetPins<E>,
+        W2: MaskedWrite<E>,
+        SP2: ShiftSpi<E>,
+    >(
+        self,
+        f: impl FnOnce((AS, PS, D, RT, RS, W, SP)) -> (AS2, PS2, D2, RT2, RS2, W2, SP2),
+    ) -> Port<E, TDI, TCK, TMS, TDO, AS2, PS2, D2, RT2, RS2, W2, SP2> {
+        let (as_pins, ps_pins, delay, rtck, reset_pins, masked, spi) = f((
+            self.as_pins,
+            self.ps_pins,
+            self.delay,
+            self.rtck,
+            self.reset_pins,
+            self.masked,
+            self.spi,
+        ));
         Port {
-            tdi,
-            tck,
-            tms,
-            tdo,
-            jtag_state: JTAGState::Reset,
-            shift_count: 0,
-            read_en: false,
-            got_clock: false,
+            tdi: self.tdi,
+            tck: self.tck,
+            tms: self.tms,
+            tdo: self.tdo,
+            as_pins,
+            as_selected: self.as_selected,
+            ps_pins,
+            mode: self.mode,
+            output_enabled: self.output_enabled,
+            jtag_state: self.jtag_state,
+            got_clock: self.got_clock,
+            tms_high: self.tms_high,
+            polarity: self.polarity,
+            delay,
+            tck_frequency: self.tck_frequency,
+            half_period_ns: self.half_period_ns,
+            rtck,
+            rtck_timeout: self.rtck_timeout,
+            reset_pins,
+            ntrst_pulse_us: self.ntrst_pulse_us,
+            nsrst_pulse_us: self.nsrst_pulse_us,
+            masked,
+            spi,
+            spi_attached: self.spi_attached,
         }
     }
 
-    #[inline]
-    pub fn handle(
-        &mut self,
-        recv_buf: &mut [u8],
-        recv_len: &mut usize,
-        send_buf: &mut [u8],
-        send_len: &mut usize,
-    ) -> Result<(), E> {
-        let mut i = 0usize;
-        while i < *recv_len && *send_len < send_buf.len() {
-            let d = recv_buf[i];
-            if self.shift_count == 0 {
-                // bit-bang mode (default)
-                self.read_en = (d & Self::BLASTER_STA_READ) != 0;
-                if d & Self::BLASTER_STA_SHIFT != 0 {
-                    // Swap to shift mode for 0 to 63 shifts
-                    self.shift_count = d & Self::BLASTER_STA_CNT_MASK;
-                    // [Record shift register content and send it to the host](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L199)
-                    // if self.read_en {
-                    //     send_buf[*send_len] = self.shift_data;
-                    //     *send_len += 1;
-                    // }
-                } else {
-                    self.set_state(d)?;
-                    if self.read_en {
-                        send_buf[*send_len] = self.get_state()?;
-                        *send_len += 1;
-                    }
-                }
-            } else {
-                // shift-mode
-                if self.read_en {
-                    send_buf[*send_len] = self.shift_io(d)?;
-                    *send_len += 1;
-                } else {
-                    self.shift_out(d)?;
-                }
-                self.shift_count -= 1;
-            }
-            i += 1;
+    /// Attach the Active Serial (AS) pins, replacing any that were attached before.
+    pub fn with_active_serial<AS2: ActiveSerialPins<E>>(
+        self,
+        as_pins: AS2,
+    ) -> Port<E, TDI, TCK, TMS, TDO, AS2, PS, D, RT, RS, W, SP> {
+        let mut port = self.map_parts(|(_, ps_pins, delay, rtck, reset_pins, masked, spi)| {
+            (as_pins, ps_pins, delay, rtck, reset_pins, masked, spi)
+        });
+        port.as_selected = false;
+        port
+    }
+
+    /// Attach the Passive Serial (PS) status pins, replacing any that were attached before, and switch to PS mode.
+    pub fn with_passive_serial<PS2: PassiveSerialPins<E>>(
+        self,
+        ps_pins: PS2,
+    ) -> Port<E, TDI, TCK, TMS, TDO, AS, PS2, D, RT, RS, W, SP> {
+        let mut port = self.map_parts(|(as_pins, _, delay, rtck, reset_pins, masked, spi)| {
+            (as_pins, ps_pins, delay, rtck, reset_pins, masked, spi)
+        });
+        port.mode = Mode::PassiveSerial;
+        port
+    }
+
+    /// Attach a delay, so that TCK stays under the frequency set with [`Port::set_tck_frequency`].
+    pub fn with_delay<D2: TckDelay>(
+        self,
+        delay: D2,
+    ) -> Port<E, TDI, TCK, TMS, TDO, AS, PS, D2, RT, RS, W, SP> {
+        self.map_parts(|(as_pins, ps_pins, _, rtck, reset_pins, masked, spi)| {
+            (as_pins, ps_pins, delay, rtck, reset_pins, masked, spi)
+        })
+    }
+
+    /// Attach a return clock (RTCK), so that bit-bang and shift-mode TCK edges wait for the target to follow.
+    pub fn with_return_clock<RT2: ReturnClock<E>>(
+        self,
+        rtck: RT2,
+    ) -> Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT2, RS, W, SP> {
+        self.map_parts(|(as_pins, ps_pins, delay, _, reset_pins, masked, spi)| {
+            (as_pins, ps_pins, delay, rtck, reset_pins, masked, spi)
+        })
+    }
+
+    /// Attach the nTRST and nSRST reset lines. They are released until asserted.
+    pub fn with_reset_pins<RS2: ResetPins<E>>(
+        self,
+        reset_pins: RS2,
+    ) -> Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT, RS2, W, SP> {
+        self.map_parts(|(as_pins, ps_pins, delay, rtck, _, masked, spi)| {
+            (as_pins, ps_pins, delay, rtck, reset_pins, masked, spi)
+        })
+    }
+
+    /// Write TDI, TCK and TMS at once with `masked` instead of pin by pin, in bit-bang and shift mode alike.
+    pub fn with_masked_write<W2: MaskedWrite<E>>(
+        self,
+        masked: W2,
+    ) -> Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT, RS, W2, SP> {
+        self.map_parts(|(as_pins, ps_pins, delay, rtck, reset_pins, _, spi)| {
+            (as_pins, ps_pins, delay, rtck, reset_pins, masked, spi)
+        })
+    }
+
+    /// Shift runs of shift-mode bytes on an SPI peripheral. The pins are taken back for bit-bang bytes.
+    pub fn with_spi<SP2: ShiftSpi<E>>(
+        self,
+        spi: SP2,
+    ) -> Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT, RS, W, SP2> {
+        let mut port = self.map_parts(|(as_pins, ps_pins, delay, rtck, reset_pins, masked, _)| {
+            (as_pins, ps_pins, delay, rtck, reset_pins, masked, spi)
+        });
+        port.spi_attached = false;
+        port
+    }
+
+    /// How long nTRST and nSRST are held low when pulsed, in microseconds. Only takes effect with a delay attached.
+    pub fn set_reset_pulse(&mut self, ntrst_us: u32, nsrst_us: u32) {
+        self.ntrst_pulse_us = ntrst_us;
+        self.nsrst_pulse_us = nsrst_us;
+    }
+
+    /// Assert or release nTRST, returning false if it is not connected.
+    /// The TAPs are held in Test-Logic-Reset while it is asserted.
+    pub fn set_ntrst(&mut self, asserted: bool) -> Result<bool, E> {
+        let connected = self.reset_pins.set_ntrst(asserted)?;
+        if connected && asserted {
+            self.jtag_state = JTAGState::Reset;
+        }
+        Ok(connected)
+    }
+
+    /// Assert or release nSRST, returning false if it is not connected
+    pub fn set_nsrst(&mut self, asserted: bool) -> Result<bool, E> {
+        self.reset_pins.set_nsrst(asserted)
+    }
+
+    /// Hold nTRST low for its pulse duration, returning false if it is not connected
+    pub fn pulse_ntrst(&mut self) -> Result<bool, E> {
+        if !self.set_ntrst(true)? {
+            return Ok(false);
         }
-        if i != 0 {
-            recv_buf.copy_within(i..*recv_len, 0);
-            *recv_len -= i;
+        self.delay
+            .delay_ns(self.ntrst_pulse_us.saturating_mul(1000));
+        self.set_ntrst(false)
+    }
+
+    /// Hold nSRST low for its pulse duration, returning false if it is not connected
+    pub fn pulse_nsrst(&mut self) -> Result<bool, E> {
+        if !self.set_nsrst(true)? {
+            return Ok(false);
         }
-        Ok(())
+        self.delay
+            .delay_ns(self.nsrst_pulse_us.saturating_mul(1000));
+        self.set_nsrst(false)
+    }
+
+    /// Wait at least `us` microseconds. Only takes effect with a delay attached.
+    pub(crate) fn wait_us(&mut self, us: u32) {
+        self.delay.delay_ns(us.saturating_mul(1000));
+    }
+
+    /// Give up on RTCK after sampling it `samples` times without it following TCK
+    pub fn set_rtck_timeout(&mut self, samples: u32) {
+        self.rtck_timeout = samples.max(1);
+    }
+
+    /// The TCK frequency limit in Hz, or 0 if there is none
+    pub fn tck_frequency(&self) -> u32 {
+        self.tck_frequency
+    }
+
+    /// Hold TCK high and low for at least half a period of `hz` each, or toggle it as fast as the pins allow if `hz` is 0.
+    /// Only takes effect with a delay attached.
+    pub fn set_tck_frequency(&mut self, hz: u32) {
+        self.tck_frequency = hz;
+        self.half_period_ns = match hz {
+            0 => 0,
+            hz => 500_000_000u32.div_ceil(hz),
+        };
+    }
+
+    /// Mark lines as inverted. Takes effect the next time each line is driven or sampled.
+    pub fn set_polarity(&mut self, polarity: Polarity) {
+        self.polarity = polarity;
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
     }
 
     fn advance(&mut self, mode: bool) {
         self.jtag_state = self.jtag_state.advance(mode);
     }
 
-    pub fn set_state(&mut self, d: u8) -> Result<(), E> {
-        if (d & Self::BLASTER_STA_OUT_TDI) >> 4 != 0 {
-            self.tdi.set_high()?;
-        } else {
-            self.tdi.set_low()?;
-        }
+    pub(crate) fn set_state(&mut self, d: u8) -> Result<(), BlasterError<E>> {
+        self.detach_spi().map_err(BlasterError::Pin)?;
+        let clk = self.set_lines(d).map_err(BlasterError::Pin)?;
+        self.edge(d & Self::BLASTER_STA_OUT_TDI != 0, clk)
+    }
+
+    /// Drive every line but TCK, returning the level TCK is to be driven to.
+    /// With a masked write, TDI and TMS are left to be written along with TCK.
+    fn set_lines(&mut self, d: u8) -> Result<bool, E> {
+        self.set_output_enable(d & Self::BLASTER_STA_OUT_OE != 0)?;
         let tms = ((d & Self::BLASTER_STA_OUT_TMS) >> 1) != 0;
-        if tms {
-            self.tms.set_high()?;
-        } else {
-            self.tms.set_low()?;
+        if !self.masked.is_available() {
+            self.set_tdi((d & Self::BLASTER_STA_OUT_TDI) >> 4 != 0)?;
+            self.set_tms(tms)?;
         }
+        self.tms_high = tms;
+        self.set_nce(d & Self::BLASTER_STA_OUT_NCE != 0)?;
+        let ncs = d & Self::BLASTER_STA_OUT_NCS != 0;
+        self.set_ncs(ncs)?;
+        self.as_selected = !ncs;
         let clk = d & Self::BLASTER_STA_OUT_TCK != 0;
+        self.track_clock(clk, tms);
+        Ok(clk)
+    }
+
+    /// Advance the JTAG state when TCK falls after having been driven high
+    fn track_clock(&mut self, clk: bool, tms: bool) {
         if self.got_clock && !clk {
-            self.advance(tms);
+            if self.mode == Mode::Jtag {
+                self.advance(tms);
+            }
             self.got_clock = false;
         }
         if clk {
             self.got_clock = true;
-            self.tck.set_high()
-        } else {
-            self.tck.set_low()
         }
     }
 
     /// [Record the state of TDO and nSTATUS](https://github.com/mithro/ixo-usb-jtag/blob/master/usbjtag.c#L184)
-    pub fn get_state(&mut self) -> Result<u8, E> {
+    pub(crate) fn get_state(&mut self) -> Result<u8, E> {
         let mut d = 0u8;
-        if self.tdo.is_high()? {
+        if self.mode == Mode::PassiveSerial {
+            if let Some(true) = self.conf_done()? {
+                d |= Self::BLASTER_STA_IN_CONF_DONE;
+            }
+            if let Some(true) = self.nstatus()? {
+                d |= Self::BLASTER_STA_IN_NSTATUS;
+            }
+            return Ok(d);
+        }
+        if self.read_tdo()? {
             d |= Self::BLASTER_STA_IN_TDO;
         }
+        if let Some(true) = self.dataout()? {
+            d |= Self::BLASTER_STA_IN_DATAOUT;
+        }
         Ok(d)
     }
 
-    pub fn reset(&mut self) -> Result<(), E> {
-        self.shift_count = 0;
-        self.read_en = false;
+    /// The line read back in shift mode: CONF_DONE in PS mode, DATAOUT while a configuration device is selected, TDO otherwise
+    fn shift_in(&self) -> Result<bool, E> {
+        if self.mode == Mode::PassiveSerial {
+            return self.conf_done().map(|conf_done| conf_done.unwrap_or(false));
+        }
+        if self.as_selected {
+            if let Some(dataout) = self.dataout()? {
+                return Ok(dataout);
+            }
+        }
+        self.read_tdo()
+    }
+
+    /// Drive or float TDI, TCK and TMS, if not already in that state
+    pub(crate) fn set_output_enable(&mut self, enable: bool) -> Result<(), E> {
+        if enable == self.output_enabled {
+            return Ok(());
+        }
+        if enable {
+            self.tdi.set_driven()?;
+            self.tck.set_driven()?;
+            self.tms.set_driven()?;
+        } else {
+            self.tdi.set_floating()?;
+            self.tck.set_floating()?;
+            self.tms.set_floating()?;
+        }
+        self.output_enabled = enable;
+        Ok(())
+    }
+
+    pub(crate) fn reset(&mut self) -> Result<(), E> {
+        let res = self.detach_spi();
+        if res.is_err() {
+            self.jtag_state = JTAGState::Undefined;
+            return res;
+        }
         self.got_clock = false;
-        let res = self.tdi.set_low();
+        self.as_selected = false;
+        let res = self.set_ncs(true).and_then(|_| self.set_nce(false));
         if res.is_err() {
             self.jtag_state = JTAGState::Undefined;
             return res;
         }
-        let res = self.tck.set_low();
+        let res = self.set_tdi(false);
         if res.is_err() {
             self.jtag_state = JTAGState::Undefined;
             return res;
         }
-        let res = self.tms.set_low();
+        let res = self.set_tck(false);
+        if res.is_err() {
+            self.jtag_state = JTAGState::Undefined;
+            return res;
+        }
+        // In PS mode TMS is nCONFIG, which must not be held low or the FPGA loses its configuration
+        self.tms_high = self.mode == Mode::PassiveSerial;
+        let res = self.set_tms(self.tms_high);
+        if res.is_err() {
+            self.jtag_state = JTAGState::Undefined;
+            return res;
+        }
+        let res = self.set_ntrst(false).and_then(|_| self.set_nsrst(false));
+        if res.is_err() {
+            self.jtag_state = JTAGState::Undefined;
+            return res.map(|_| ());
+        }
+        // Release the lines until the host sets OE
+        let res = self.set_output_enable(false);
         if res.is_err() {
             self.jtag_state = JTAGState::Undefined;
             return res;
@@ -259,35 +582,333 @@ where
         Ok(())
     }
 
-    fn shift_out(&mut self, mut shift_data: u8) -> Result<(), E> {
+    fn shift_out(&mut self, mut shift_data: u8) -> Result<(), BlasterError<E>> {
+        self.detach_spi().map_err(BlasterError::Pin)?;
+        if self.masked.is_available() {
+            return self.shift_masked(shift_data, false).map(|_| ());
+        }
         for _i in 0..8 {
-            if shift_data & 1 != 0 {
-                self.tdi.set_high()?;
-            } else {
-                self.tdi.set_low()?;
-            }
-            self.tck.set_high()?;
+            self.set_tdi(shift_data & 1 != 0)
+                .map_err(BlasterError::Pin)?;
+            self.follow_edge(true)?;
             shift_data >>= 1;
-            self.tck.set_low()?;
+            self.follow_edge(false)?;
         }
         Ok(())
     }
 
-    fn shift_io(&mut self, mut shift_data: u8) -> Result<u8, E> {
+    fn shift_io(&mut self, mut shift_data: u8) -> Result<u8, BlasterError<E>> {
+        self.detach_spi().map_err(BlasterError::Pin)?;
+        if self.masked.is_available() {
+            return self.shift_masked(shift_data, true);
+        }
         for _i in 0..8 {
-            if shift_data & 1 != 0 {
-                self.tdi.set_high()?;
-            } else {
-                self.tdi.set_low()?;
+            self.set_tdi(shift_data & 1 != 0)
+                .map_err(BlasterError::Pin)?;
+            let din = self.shift_in().map_err(BlasterError::Pin)?;
+            self.follow_edge(true)?;
+            shift_data >>= 1;
+            if din {
+                shift_data |= 0b1000_0000u8;
             }
-            let din = self.tdo.is_high()?;
-            self.tck.set_high()?;
+            self.follow_edge(false)?;
+        }
+        Ok(shift_data)
+    }
+
+    /// Whether shift-mode bytes can go to the SPI peripheral. It only reads TDO, not DATAOUT or CONF_DONE, cannot invert the lines,
+    /// would drive lines the host left floating and does not wait for RTCK.
+    fn spi_usable(&self) -> bool {
+        self.spi.is_available()
+            && self.output_enabled
+            && self.mode == Mode::Jtag
+            && !self.rtck.is_connected()
+            && !(self.as_selected && self.as_pins.is_dataout_connected())
+            && !self.polarity.tdi
+            && !self.polarity.tck
+            && !self.polarity.tdo
+    }
+
+    /// Shift bytes on the SPI peripheral, handing it the pins first if need be
+    fn shift_spi(&mut self, data: &mut [u8], read: bool) -> Result<(), BlasterError<E>> {
+        if !self.spi_attached {
+            self.spi.attach().map_err(BlasterError::Pin)?;
+            self.spi_attached = true;
+        }
+        let res = self.spi.shift(data, read);
+        if res.is_err() {
+            self.jtag_state = JTAGState::Undefined;
+        }
+        res
+    }
+
+    /// Take TDI and TCK back from the SPI peripheral, if it has them
+    fn detach_spi(&mut self) -> Result<(), E> {
+        if self.spi_attached {
+            self.spi.detach()?;
+            self.spi_attached = false;
+        }
+        Ok(())
+    }
+
+    /// Shift a byte with TDI written along with TCK. TDI changes on the falling edge, so it has settled by the rising edge.
+    fn shift_masked(&mut self, mut shift_data: u8, read: bool) -> Result<u8, BlasterError<E>> {
+        let mut tdi = shift_data & 1 != 0;
+        // TCK is already low, so this only sets up the first bit
+        self.write_masked(tdi, false).map_err(BlasterError::Pin)?;
+        for i in 0..8 {
+            let din = read && self.shift_in().map_err(BlasterError::Pin)?;
+            self.masked_edge(tdi, true)?;
             shift_data >>= 1;
             if din {
                 shift_data |= 0b1000_0000u8;
             }
-            self.tck.set_low()?;
+            // TDI stays at the last bit after the byte, as it does when shifted pin by pin
+            if i != 7 {
+                tdi = shift_data & 1 != 0;
+            }
+            self.masked_edge(tdi, false)?;
         }
         Ok(shift_data)
     }
+
+    /// Drive TCK, TDI and TMS to the given levels, for protocols that bit-bang the pins directly.
+    /// The JTAG state advances when TCK falls, as it does for bit-bang bytes.
+    pub(crate) fn drive(&mut self, tck: bool, tdi: bool, tms: bool) -> Result<(), BlasterError<E>> {
+        if !self.masked.is_available() {
+            self.set_tdi(tdi).map_err(BlasterError::Pin)?;
+            self.set_tms(tms).map_err(BlasterError::Pin)?;
+        }
+        self.tms_high = tms;
+        self.track_clock(tck, tms);
+        self.edge(tdi, tck)
+    }
+
+    /// Sample TDO
+    pub(crate) fn tdo(&self) -> Result<bool, E> {
+        self.read_tdo()
+    }
+
+    /// Drive TDI, then pulse TCK once with TMS held at its last level.
+    /// Returns TDO as sampled before the rising edge.
+    pub(crate) fn clock_tdi(&mut self, tdi: bool) -> Result<bool, BlasterError<E>> {
+        let tms = self.tms_high;
+        self.clock_tms(tms, tdi)
+    }
+
+    /// Drive TMS and TDI, then pulse TCK once, advancing the JTAG state.
+    /// Returns TDO as sampled before the rising edge.
+    pub(crate) fn clock_tms(&mut self, tms: bool, tdi: bool) -> Result<bool, BlasterError<E>> {
+        if self.masked.is_available() {
+            self.tms_high = tms;
+            // TCK is already low, so this only sets up TDI and TMS
+            self.write_masked(tdi, false).map_err(BlasterError::Pin)?;
+        } else {
+            self.set_tdi(tdi).map_err(BlasterError::Pin)?;
+            if tms != self.tms_high {
+                self.set_tms(tms).map_err(BlasterError::Pin)?;
+                self.tms_high = tms;
+            }
+        }
+        let tdo = self.read_tdo().map_err(BlasterError::Pin)?;
+        self.edge(tdi, true)?;
+        if self.mode == Mode::Jtag {
+            self.advance(tms);
+        }
+        self.got_clock = false;
+        self.edge(tdi, false)?;
+        Ok(tdo)
+    }
+
+    /// Drive TCK, then hold it for half a period
+    fn clock_edge(&mut self, high: bool) -> Result<(), E> {
+        self.set_tck(high)?;
+        if self.half_period_ns != 0 {
+            self.delay.delay_ns(self.half_period_ns);
+        }
+        Ok(())
+    }
+
+    /// Write TDI, TCK and TMS at once, TMS staying at its last level, then hold TCK for half a period
+    fn write_masked(&mut self, tdi: bool, tck: bool) -> Result<(), E> {
+        let polarity = self.polarity;
+        self.masked.write_lines(
+            tdi != polarity.tdi,
+            tck != polarity.tck,
+            self.tms_high != polarity.tms,
+        )?;
+        if self.half_period_ns != 0 {
+            self.delay.delay_ns(self.half_period_ns);
+        }
+        Ok(())
+    }
+
+    /// Drive TCK, written along with TDI if there is a masked write, then wait for RTCK to follow if there is one
+    fn edge(&mut self, tdi: bool, high: bool) -> Result<(), BlasterError<E>> {
+        if self.masked.is_available() {
+            self.masked_edge(tdi, high)
+        } else {
+            self.follow_edge(high)
+        }
+    }
+
+    /// Drive TCK, then wait for RTCK to follow if there is one
+    fn follow_edge(&mut self, high: bool) -> Result<(), BlasterError<E>> {
+        self.clock_edge(high).map_err(BlasterError::Pin)?;
+        self.wait_rtck(high)
+    }
+
+    /// Write TDI and TCK at once, then wait for RTCK to follow if there is one
+    fn masked_edge(&mut self, tdi: bool, high: bool) -> Result<(), BlasterError<E>> {
+        self.write_masked(tdi, high).map_err(BlasterError::Pin)?;
+        self.wait_rtck(high)
+    }
+
+    /// Wait for RTCK to reach the level TCK was driven to.
+    /// On a timeout the JTAG state is left undefined, as the target may have missed the edge.
+    fn wait_rtck(&mut self, high: bool) -> Result<(), BlasterError<E>> {
+        for _ in 0..self.rtck_timeout {
+            match self.read_rtck().map_err(BlasterError::Pin)? {
+                None => return Ok(()),
+                Some(rtck) if rtck == high => return Ok(()),
+                Some(_) => {}
+            }
+        }
+        self.jtag_state = JTAGState::Undefined;
+        Err(BlasterError::ReturnClockTimeout)
+    }
+
+    fn set_tdi(&mut self, high: bool) -> Result<(), E> {
+        set_level(&mut self.tdi, high != self.polarity.tdi)
+    }
+
+    fn set_tck(&mut self, high: bool) -> Result<(), E> {
+        set_level(&mut self.tck, high != self.polarity.tck)
+    }
+
+    fn set_tms(&mut self, high: bool) -> Result<(), E> {
+        set_level(&mut self.tms, high != self.polarity.tms)
+    }
+
+    fn read_tdo(&self) -> Result<bool, E> {
+        self.tdo.is_high().map(|high| high != self.polarity.tdo)
+    }
+
+    fn read_rtck(&self) -> Result<Option<bool>, E> {
+        let inverted = self.polarity.rtck;
+        self.rtck
+            .rtck()
+            .map(|level| level.map(|high| high != inverted))
+    }
+
+    fn set_nce(&mut self, high: bool) -> Result<(), E> {
+        self.as_pins.set_nce(high != self.polarity.nce)
+    }
+
+    fn set_ncs(&mut self, high: bool) -> Result<(), E> {
+        self.as_pins.set_ncs(high != self.polarity.ncs)
+    }
+
+    fn dataout(&self) -> Result<Option<bool>, E> {
+        let inverted = self.polarity.dataout;
+        self.as_pins
+            .dataout()
+            .map(|level| level.map(|high| high != inverted))
+    }
+
+    fn nstatus(&self) -> Result<Option<bool>, E> {
+        let inverted = self.polarity.nstatus;
+        self.ps_pins
+            .nstatus()
+            .map(|level| level.map(|high| high != inverted))
+    }
+
+    fn conf_done(&self) -> Result<Option<bool>, E> {
+        let inverted = self.polarity.conf_done;
+        self.ps_pins
+            .conf_done()
+            .map(|level| level.map(|high| high != inverted))
+    }
+}
+
+impl<
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+        AS: ActiveSerialPins<E>,
+        PS: PassiveSerialPins<E>,
+        D: TckDelay,
+        RT: ReturnClock<E>,
+        RS: ResetPins<E>,
+        W: MaskedWrite<E>,
+        SP: ShiftSpi<E>,
+    > JtagBackend for Port<E, TDI, TCK, TMS, TDO, AS, PS, D, RT, RS, W, SP>
+{
+    type Error = E;
+
+    fn bit_bang(&mut self, byte: u8) -> Result<(), BlasterError<E>> {
+        self.set_state(byte)
+    }
+
+    fn read_status(&mut self) -> Result<u8, BlasterError<E>> {
+        self.get_state().map_err(BlasterError::Pin)
+    }
+
+    fn shift_byte(&mut self, byte: u8, read: bool) -> Result<u8, BlasterError<E>> {
+        if read {
+            self.shift_io(byte)
+        } else {
+            self.shift_out(byte).map(|_| byte)
+        }
+    }
+
+    fn shift_bytes(&mut self, data: &mut [u8], read: bool) -> Result<(), BlasterError<E>> {
+        if self.spi_usable() {
+            return self.shift_spi(data, read);
+        }
+        for byte in data.iter_mut() {
+            *byte = self.shift_byte(*byte, read)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), E> {
+        Port::reset(self)
+    }
+
+    fn tck_frequency(&self) -> u32 {
+        Port::tck_frequency(self)
+    }
+
+    fn set_tck_frequency(&mut self, hz: u32) -> bool {
+        Port::set_tck_frequency(self, hz);
+        true
+    }
+
+    fn set_ntrst(&mut self, asserted: bool) -> Result<bool, E> {
+        Port::set_ntrst(self, asserted)
+    }
+
+    fn set_nsrst(&mut self, asserted: bool) -> Result<bool, E> {
+        Port::set_nsrst(self, asserted)
+    }
+
+    fn pulse_ntrst(&mut self) -> Result<bool, E> {
+        Port::pulse_ntrst(self)
+    }
+
+    fn pulse_nsrst(&mut self) -> Result<bool, E> {
+        Port::pulse_nsrst(self)
+    }
+}
+
+/// Drive a pin to a physical level
+fn set_level<P: OutputPin>(pin: &mut P, high: bool) -> Result<(), P::Error> {
+    if high {
+        pin.set_high()
+    } else {
+        pin.set_low()
+    }
 }
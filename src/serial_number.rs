@@ -0,0 +1,31 @@
+/// Length of a serial number derived from a unique ID, in characters
+const LEN: usize = 16;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+/// A serial number string derived from an MCU unique ID, so that several blasters on one host can be told apart.
+/// Pass the same string to [`Blaster::with_serial_number`](crate::Blaster::with_serial_number) and to the `UsbDeviceBuilder`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SerialNumber {
+    buf: [u8; LEN],
+}
+
+impl SerialNumber {
+    /// Hex-encode `id`. IDs longer than 8 bytes are folded with XOR, so that the serial number still fits in the FT245 EEPROM.
+    pub fn from_unique_id(id: &[u8]) -> SerialNumber {
+        let mut folded = [0u8; LEN / 2];
+        for (i, b) in id.iter().enumerate() {
+            folded[i % folded.len()] ^= b;
+        }
+        let mut buf = [0u8; LEN];
+        for (i, b) in folded.iter().enumerate() {
+            buf[2 * i] = HEX_DIGITS[(b >> 4) as usize];
+            buf[2 * i + 1] = HEX_DIGITS[(b & 0xF) as usize];
+        }
+        SerialNumber { buf }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf).expect("serial number is not ASCII")
+    }
+}
@@ -0,0 +1,100 @@
+use hal::blocking::spi::{Transfer, Write};
+
+use crate::error::BlasterError;
+
+/// Bytes bit-reversed at a time for a peripheral that shifts MSB first
+const REVERSE_CHUNK: usize = 16;
+
+/// Shifts shift-mode bytes on an SPI peripheral wired to TDI (MOSI), TCK (SCK) and TDO (MISO), instead of bit-banging them.
+/// Use `()` to bit-bang every byte.
+pub trait ShiftSpi<E> {
+    /// Whether there is a peripheral at all
+    fn is_available(&self) -> bool {
+        true
+    }
+    /// Hand TDI and TCK over to the peripheral
+    fn attach(&mut self) -> Result<(), E>;
+    /// Give TDI and TCK back to their GPIO pins
+    fn detach(&mut self) -> Result<(), E>;
+    /// Shift `data` out LSB first in SPI mode 0. If `read`, replace it with the bits sampled on TDO, otherwise leave it as it is.
+    fn shift(&mut self, data: &mut [u8], read: bool) -> Result<(), BlasterError<E>>;
+}
+
+impl<E> ShiftSpi<E> for () {
+    fn is_available(&self) -> bool {
+        false
+    }
+
+    fn attach(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn shift(&mut self, _data: &mut [u8], _read: bool) -> Result<(), BlasterError<E>> {
+        Err(BlasterError::Spi)
+    }
+}
+
+/// Switches TDI and TCK between their GPIO pins and an SPI peripheral, such as by changing the pin multiplexer.
+pub trait SpiMux<E> {
+    /// Route TDI and TCK to the SPI peripheral
+    fn to_spi(&mut self) -> Result<(), E>;
+    /// Route TDI and TCK back to their GPIO pins
+    fn to_gpio(&mut self) -> Result<(), E>;
+}
+
+/// An `embedded_hal` blocking SPI peripheral set up for mode 0, with the pin multiplexer that hands it TDI and TCK.
+/// Set `msb_first` if the peripheral cannot shift LSB first, and bytes will be bit-reversed in software.
+pub struct SpiShift<SPI, M> {
+    pub spi: SPI,
+    pub mux: M,
+    pub msb_first: bool,
+}
+
+impl<E, SPI, M> ShiftSpi<E> for SpiShift<SPI, M>
+where
+    SPI: Write<u8> + Transfer<u8>,
+    M: SpiMux<E>,
+{
+    fn attach(&mut self) -> Result<(), E> {
+        self.mux.to_spi()
+    }
+
+    fn detach(&mut self) -> Result<(), E> {
+        self.mux.to_gpio()
+    }
+
+    fn shift(&mut self, data: &mut [u8], read: bool) -> Result<(), BlasterError<E>> {
+        if read {
+            if self.msb_first {
+                reverse_bits(data);
+            }
+            self.spi.transfer(data).map_err(|_| BlasterError::Spi)?;
+            if self.msb_first {
+                reverse_bits(data);
+            }
+            Ok(())
+        } else if self.msb_first {
+            // Reverse a copy, so that the bytes are left as they were
+            let mut reversed = [0u8; REVERSE_CHUNK];
+            for chunk in data.chunks(REVERSE_CHUNK) {
+                let reversed = &mut reversed[..chunk.len()];
+                reversed.copy_from_slice(chunk);
+                reverse_bits(reversed);
+                self.spi.write(reversed).map_err(|_| BlasterError::Spi)?;
+            }
+            Ok(())
+        } else {
+            self.spi.write(data).map_err(|_| BlasterError::Spi)
+        }
+    }
+}
+
+fn reverse_bits(data: &mut [u8]) {
+    for byte in data.iter_mut() {
+        *byte = byte.reverse_bits();
+    }
+}
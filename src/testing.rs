@@ -0,0 +1,8 @@
+//! Test doubles for driving the blaster on the host, without any hardware attached.
+//! Only available with the `std` feature.
+
+mod bus;
+mod tap;
+
+pub use bus::{MockBus, MockHost, Stalled};
+pub use tap::{JtagChain, SimTck, SimTdi, SimTdo, SimTms, TapDevice, TapState};
@@ -0,0 +1,370 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use usb_device::bus::{PollResult, UsbBus};
+use usb_device::class::UsbClass;
+use usb_device::control::Request;
+use usb_device::device::UsbDevice;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+const MAX_ENDPOINTS: usize = 16;
+
+/// How many times the device is polled for one step of a transfer before giving up
+const MAX_POLLS: usize = 64;
+
+/// The control transfer was rejected by the device.
+#[derive(Debug, PartialEq)]
+pub struct Stalled;
+
+#[derive(Default)]
+struct Endpoint {
+    allocated: bool,
+    max_packet_size: u16,
+    stalled: bool,
+    /// OUT: packets sent by the host and not yet read by the device.
+    /// IN: packets written by the device and not yet taken by the host (at most one).
+    packets: VecDeque<Vec<u8>>,
+    /// IN: how many bytes of the next packet the device writes are accepted
+    accept: Option<usize>,
+}
+
+#[derive(Default)]
+struct State {
+    out_eps: [Endpoint; MAX_ENDPOINTS],
+    in_eps: [Endpoint; MAX_ENDPOINTS],
+    setup: Option<[u8; 8]>,
+    reset: bool,
+    in_complete: u16,
+    address: u8,
+}
+
+impl State {
+    fn endpoint(&mut self, ep_addr: EndpointAddress) -> Result<&mut Endpoint> {
+        let eps = match ep_addr.direction() {
+            UsbDirection::Out => &mut self.out_eps,
+            UsbDirection::In => &mut self.in_eps,
+        };
+        match eps.get_mut(ep_addr.index()) {
+            Some(ep) if ep.allocated => Ok(ep),
+            _ => Err(UsbError::InvalidEndpoint),
+        }
+    }
+}
+
+/// An in-memory [`UsbBus`] that stands in for the USB peripheral.
+///
+/// The host side of the bus is reached through [`UsbDevice::bus`]: bulk OUT packets are queued with
+/// [`MockBus::bulk_out`] and bulk IN packets are taken with [`MockBus::bulk_in`]. Resets and control
+/// transfers also need the device to be polled, so they are provided by [`MockHost`].
+#[derive(Default)]
+pub struct MockBus {
+    state: Mutex<State>,
+}
+
+impl MockBus {
+    pub fn new() -> MockBus {
+        MockBus::default()
+    }
+
+    /// Signal a USB reset, which the device picks up on its next poll.
+    pub fn host_reset(&self) {
+        self.state.lock().unwrap().reset = true;
+    }
+
+    /// Queue a packet from the host on an OUT endpoint.
+    pub fn bulk_out(&self, ep: u8, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let ep = state
+            .endpoint(EndpointAddress::from_parts(ep as usize, UsbDirection::Out))
+            .expect("OUT endpoint is not allocated");
+        assert!(
+            data.len() <= ep.max_packet_size as usize,
+            "packet is larger than the endpoint's max packet size"
+        );
+        ep.packets.push_back(data.to_vec());
+    }
+
+    /// Take the packet the device wrote to an IN endpoint, if any.
+    /// The device is told that the transfer completed on its next poll.
+    pub fn bulk_in(&self, ep: u8) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let packet = state
+            .endpoint(EndpointAddress::from_parts(ep as usize, UsbDirection::In))
+            .expect("IN endpoint is not allocated")
+            .packets
+            .pop_front();
+        if packet.is_some() {
+            state.in_complete |= 1 << ep;
+        }
+        packet
+    }
+
+    /// Accept only the first `len` bytes of the next packet the device writes to an IN endpoint,
+    /// as a peripheral short on buffer space might.
+    pub fn accept_short(&self, ep: u8, len: usize) {
+        self.state
+            .lock()
+            .unwrap()
+            .endpoint(EndpointAddress::from_parts(ep as usize, UsbDirection::In))
+            .expect("IN endpoint is not allocated")
+            .accept = Some(len);
+    }
+
+    /// The address assigned to the device by the host, or 0 before SET_ADDRESS.
+    pub fn address(&self) -> u8 {
+        self.state.lock().unwrap().address
+    }
+
+    fn setup(&self, req: &Request) {
+        let mut state = self.state.lock().unwrap();
+        let rt = (req.direction as u8) | ((req.request_type as u8) << 5) | (req.recipient as u8);
+        state.setup = Some([
+            rt,
+            req.request,
+            req.value as u8,
+            (req.value >> 8) as u8,
+            req.index as u8,
+            (req.index >> 8) as u8,
+            req.length as u8,
+            (req.length >> 8) as u8,
+        ]);
+        // A SETUP packet is always accepted and clears the control endpoint's STALL condition
+        state.out_eps[0].stalled = false;
+        state.in_eps[0].stalled = false;
+        state.out_eps[0].packets.clear();
+        state.in_eps[0].packets.clear();
+    }
+
+    fn control_stalled(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.out_eps[0].stalled || state.in_eps[0].stalled
+    }
+
+    fn control_max_packet_size(&self) -> usize {
+        self.state.lock().unwrap().out_eps[0].max_packet_size as usize
+    }
+}
+
+impl UsbBus for MockBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        let state = self.state.get_mut().unwrap();
+        let eps = match ep_dir {
+            UsbDirection::Out => &mut state.out_eps,
+            UsbDirection::In => &mut state.in_eps,
+        };
+        let index = match ep_addr {
+            Some(addr) if eps[addr.index()].allocated => return Err(UsbError::InvalidEndpoint),
+            Some(addr) => addr.index(),
+            None => (1..MAX_ENDPOINTS)
+                .find(|&i| !eps[i].allocated)
+                .ok_or(UsbError::EndpointOverflow)?,
+        };
+        eps[index].allocated = true;
+        eps[index].max_packet_size = max_packet_size;
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {
+        let state = &mut *self.state.lock().unwrap();
+        for ep in state.out_eps.iter_mut().chain(state.in_eps.iter_mut()) {
+            ep.stalled = false;
+            ep.packets.clear();
+        }
+        state.setup = None;
+        state.in_complete = 0;
+        state.address = 0;
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        self.state.lock().unwrap().address = addr;
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let ep = state.endpoint(ep_addr)?;
+        if !ep.packets.is_empty() {
+            return Err(UsbError::WouldBlock);
+        }
+        if buf.len() > ep.max_packet_size as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+        let len = ep.accept.take().map_or(buf.len(), |len| len.min(buf.len()));
+        ep.packets.push_back(buf[..len].to_vec());
+        Ok(len)
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if ep_addr.index() == 0 {
+            if let Some(setup) = state.setup.take() {
+                if buf.len() < setup.len() {
+                    return Err(UsbError::BufferOverflow);
+                }
+                buf[..setup.len()].copy_from_slice(&setup);
+                return Ok(setup.len());
+            }
+        }
+        let ep = state.endpoint(ep_addr)?;
+        let packet = ep.packets.pop_front().ok_or(UsbError::WouldBlock)?;
+        if buf.len() < packet.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        if let Ok(ep) = self.state.lock().unwrap().endpoint(ep_addr) {
+            ep.stalled = stalled;
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .endpoint(ep_addr)
+            .map(|ep| ep.stalled)
+            .unwrap_or(false)
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let mut state = self.state.lock().unwrap();
+        if state.reset {
+            state.reset = false;
+            return PollResult::Reset;
+        }
+        let ep_setup = if state.setup.is_some() { 1 } else { 0 };
+        let ep_out = state
+            .out_eps
+            .iter()
+            .enumerate()
+            .filter(|(_, ep)| !ep.packets.is_empty())
+            .fold(0u16, |bits, (i, _)| bits | (1 << i));
+        let ep_in_complete = state.in_complete;
+        state.in_complete = 0;
+        if ep_setup | ep_out | ep_in_complete == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
+
+/// Host-side operations on a [`UsbDevice`] attached to a [`MockBus`].
+/// Each operation polls the device with `classes` until it has responded.
+pub trait MockHost {
+    /// Reset the bus, as a host does before enumeration.
+    fn host_reset(&mut self, classes: &mut [&mut dyn UsbClass<MockBus>]);
+
+    /// Perform a control transfer with an IN data stage and return the data sent by the device.
+    fn control_in(
+        &mut self,
+        classes: &mut [&mut dyn UsbClass<MockBus>],
+        req: Request,
+    ) -> core::result::Result<Vec<u8>, Stalled>;
+
+    /// Perform a control transfer with an optional OUT data stage.
+    fn control_out(
+        &mut self,
+        classes: &mut [&mut dyn UsbClass<MockBus>],
+        req: Request,
+        data: &[u8],
+    ) -> core::result::Result<(), Stalled>;
+}
+
+impl MockHost for UsbDevice<'_, MockBus> {
+    fn host_reset(&mut self, classes: &mut [&mut dyn UsbClass<MockBus>]) {
+        self.bus().host_reset();
+        self.poll(classes);
+    }
+
+    fn control_in(
+        &mut self,
+        classes: &mut [&mut dyn UsbClass<MockBus>],
+        req: Request,
+    ) -> core::result::Result<Vec<u8>, Stalled> {
+        assert_eq!(req.direction, UsbDirection::In);
+        let max_packet_size = self.bus().control_max_packet_size();
+        self.bus().setup(&req);
+        let mut data = Vec::new();
+        let mut polls = 0;
+        loop {
+            self.poll(classes);
+            if self.bus().control_stalled() {
+                return Err(Stalled);
+            }
+            if let Some(packet) = self.bus().bulk_in(0) {
+                let short = packet.len() < max_packet_size;
+                data.extend_from_slice(&packet);
+                if short || data.len() >= req.length as usize {
+                    break;
+                }
+            }
+            polls += 1;
+            assert!(polls < MAX_POLLS, "device did not complete the data stage");
+        }
+        // Let the device see the last IN packet complete, then send the zero-length status packet
+        self.poll(classes);
+        self.bus().bulk_in(0);
+        self.bus().bulk_out(0, &[]);
+        self.poll(classes);
+        Ok(data)
+    }
+
+    fn control_out(
+        &mut self,
+        classes: &mut [&mut dyn UsbClass<MockBus>],
+        req: Request,
+        data: &[u8],
+    ) -> core::result::Result<(), Stalled> {
+        assert_eq!(req.direction, UsbDirection::Out);
+        assert_eq!(req.length as usize, data.len());
+        let max_packet_size = self.bus().control_max_packet_size();
+        self.bus().setup(&req);
+        self.poll(classes);
+        for chunk in data.chunks(max_packet_size) {
+            if self.bus().control_stalled() {
+                return Err(Stalled);
+            }
+            self.bus().bulk_out(0, chunk);
+            self.poll(classes);
+        }
+        let mut polls = 0;
+        loop {
+            if self.bus().control_stalled() {
+                return Err(Stalled);
+            }
+            if self.bus().bulk_in(0).is_some() {
+                break;
+            }
+            polls += 1;
+            assert!(
+                polls < MAX_POLLS,
+                "device did not complete the status stage"
+            );
+            self.poll(classes);
+        }
+        self.poll(classes);
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+use core::cell::RefCell;
+use core::convert::Infallible;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use hal::digital::v2::{InputPin, OutputPin};
+
+/// The IEEE 1149.1 TAP controller states.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TapState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDRScan,
+    CaptureDR,
+    ShiftDR,
+    Exit1DR,
+    PauseDR,
+    Exit2DR,
+    UpdateDR,
+    SelectIRScan,
+    CaptureIR,
+    ShiftIR,
+    Exit1IR,
+    PauseIR,
+    Exit2IR,
+    UpdateIR,
+}
+
+impl TapState {
+    /// The state entered on a rising TCK edge with the given TMS level
+    pub fn next(self, tms: bool) -> TapState {
+        use TapState::*;
+        match (self, tms) {
+            (TestLogicReset, false) => RunTestIdle,
+            (TestLogicReset, true) => TestLogicReset,
+            (RunTestIdle, false) => RunTestIdle,
+            (RunTestIdle, true) => SelectDRScan,
+            (SelectDRScan, false) => CaptureDR,
+            (SelectDRScan, true) => SelectIRScan,
+            (CaptureDR, false) => ShiftDR,
+            (CaptureDR, true) => Exit1DR,
+            (ShiftDR, false) => ShiftDR,
+            (ShiftDR, true) => Exit1DR,
+            (Exit1DR, false) => PauseDR,
+            (Exit1DR, true) => UpdateDR,
+            (PauseDR, false) => PauseDR,
+            (PauseDR, true) => Exit2DR,
+            (Exit2DR, false) => ShiftDR,
+            (Exit2DR, true) => UpdateDR,
+            (UpdateDR, false) => RunTestIdle,
+            (UpdateDR, true) => SelectDRScan,
+            (SelectIRScan, false) => CaptureIR,
+            (SelectIRScan, true) => TestLogicReset,
+            (CaptureIR, false) => ShiftIR,
+            (CaptureIR, true) => Exit1IR,
+            (ShiftIR, false) => ShiftIR,
+            (ShiftIR, true) => Exit1IR,
+            (Exit1IR, false) => PauseIR,
+            (Exit1IR, true) => UpdateIR,
+            (PauseIR, false) => PauseIR,
+            (PauseIR, true) => Exit2IR,
+            (Exit2IR, false) => ShiftIR,
+            (Exit2IR, true) => UpdateIR,
+            (UpdateIR, false) => RunTestIdle,
+            (UpdateIR, true) => SelectDRScan,
+        }
+    }
+}
+
+/// A single simulated device in a JTAG chain, with IR, BYPASS and optionally IDCODE registers.
+#[derive(Clone, Debug)]
+pub struct TapDevice {
+    ir_len: u8,
+    idcode: Option<(u32, u32)>,
+    state: TapState,
+    ir: u32,
+    ir_shift: u32,
+    dr_shift: u32,
+    dr_len: u8,
+}
+
+impl TapDevice {
+    /// The value loaded into the instruction shift register in Capture-IR, as required by IEEE 1149.1
+    pub const IR_CAPTURE: u32 = 0b01;
+
+    /// A device with an `ir_len` bit instruction register that reports `idcode` when `idcode_instruction` is loaded.
+    /// IDCODE is selected after Test-Logic-Reset.
+    pub fn new(idcode: u32, ir_len: u8, idcode_instruction: u32) -> TapDevice {
+        TapDevice::with_idcode(ir_len, Some((idcode, idcode_instruction)))
+    }
+
+    /// A device with an `ir_len` bit instruction register and no IDCODE register.
+    /// BYPASS is selected after Test-Logic-Reset.
+    pub fn bypass_only(ir_len: u8) -> TapDevice {
+        TapDevice::with_idcode(ir_len, None)
+    }
+
+    fn with_idcode(ir_len: u8, idcode: Option<(u32, u32)>) -> TapDevice {
+        assert!((2..=32).contains(&ir_len), "IR length must be 2 to 32 bits");
+        let mut device = TapDevice {
+            ir_len,
+            idcode,
+            state: TapState::TestLogicReset,
+            ir: 0,
+            ir_shift: 0,
+            dr_shift: 0,
+            dr_len: 1,
+        };
+        device.test_logic_reset();
+        device
+    }
+
+    pub fn state(&self) -> TapState {
+        self.state
+    }
+
+    /// The instruction last latched in Update-IR
+    pub fn ir(&self) -> u32 {
+        self.ir
+    }
+
+    pub fn ir_len(&self) -> u8 {
+        self.ir_len
+    }
+
+    fn bypass_instruction(&self) -> u32 {
+        (((1u64) << self.ir_len) - 1) as u32
+    }
+
+    fn test_logic_reset(&mut self) {
+        self.ir = match self.idcode {
+            Some((_, instruction)) => instruction,
+            None => self.bypass_instruction(),
+        };
+    }
+
+    /// The level this device drives on its TDO
+    fn tdo(&self) -> bool {
+        match self.state {
+            TapState::ShiftIR => self.ir_shift & 1 != 0,
+            TapState::ShiftDR => self.dr_shift & 1 != 0,
+            // TDO is at high impedance, pulled up
+            _ => true,
+        }
+    }
+
+    fn shift(register: &mut u32, len: u8, tdi: bool) {
+        *register >>= 1;
+        if tdi {
+            *register |= 1 << (len - 1);
+        }
+    }
+
+    /// Rising TCK edge: sample TDI and TMS
+    fn clock(&mut self, tdi: bool, tms: bool) {
+        match self.state {
+            TapState::CaptureIR => self.ir_shift = Self::IR_CAPTURE,
+            TapState::CaptureDR => match self.idcode {
+                Some((idcode, instruction)) if instruction == self.ir => {
+                    self.dr_shift = idcode;
+                    self.dr_len = 32;
+                }
+                _ => {
+                    self.dr_shift = 0;
+                    self.dr_len = 1;
+                }
+            },
+            TapState::ShiftIR => Self::shift(&mut self.ir_shift, self.ir_len, tdi),
+            TapState::ShiftDR => Self::shift(&mut self.dr_shift, self.dr_len, tdi),
+            _ => {}
+        }
+        self.state = self.state.next(tms);
+        match self.state {
+            TapState::TestLogicReset => self.test_logic_reset(),
+            TapState::UpdateIR => self.ir = self.ir_shift,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Chain {
+    devices: Vec<TapDevice>,
+    tdi: bool,
+    tck: bool,
+    tms: bool,
+    rising_edges: usize,
+}
+
+impl Chain {
+    fn tdo(&self) -> bool {
+        self.devices.last().map(TapDevice::tdo).unwrap_or(self.tdi)
+    }
+
+    fn rising_edge(&mut self) {
+        let mut tdi = self.tdi;
+        for device in self.devices.iter_mut() {
+            let tdo = device.tdo();
+            device.clock(tdi, self.tms);
+            tdi = tdo;
+        }
+        self.rising_edges += 1;
+    }
+}
+
+/// A simulated daisy chain of JTAG devices, driven through its four pins.
+///
+/// TDI enters the first device and TDO leaves the last one. Handles are cheap to clone and all refer to the same chain.
+#[derive(Clone, Debug)]
+pub struct JtagChain {
+    chain: Rc<RefCell<Chain>>,
+}
+
+impl JtagChain {
+    pub fn new(devices: Vec<TapDevice>) -> JtagChain {
+        JtagChain {
+            chain: Rc::new(RefCell::new(Chain {
+                devices,
+                tdi: false,
+                tck: false,
+                tms: false,
+                rising_edges: 0,
+            })),
+        }
+    }
+
+    /// The TDI, TCK, TMS and TDO pins of the chain, in the order [`Blaster::new`](crate::Blaster::new) takes them.
+    pub fn pins(&self) -> (SimTdi, SimTck, SimTms, SimTdo) {
+        (
+            SimTdi(self.clone()),
+            SimTck(self.clone()),
+            SimTms(self.clone()),
+            SimTdo(self.clone()),
+        )
+    }
+
+    /// A snapshot of the device at `index`, counting from TDI
+    pub fn device(&self, index: usize) -> TapDevice {
+        self.chain.borrow().devices[index].clone()
+    }
+
+    /// The TAP state of the device at `index`
+    pub fn state(&self, index: usize) -> TapState {
+        self.chain.borrow().devices[index].state()
+    }
+
+    /// The number of rising TCK edges seen so far
+    pub fn rising_edges(&self) -> usize {
+        self.chain.borrow().rising_edges
+    }
+}
+
+/// Simulated TDI pin of a [`JtagChain`]
+pub struct SimTdi(JtagChain);
+
+/// Simulated TCK pin of a [`JtagChain`]
+pub struct SimTck(JtagChain);
+
+/// Simulated TMS pin of a [`JtagChain`]
+pub struct SimTms(JtagChain);
+
+/// Simulated TDO pin of a [`JtagChain`]
+pub struct SimTdo(JtagChain);
+
+impl OutputPin for SimTdi {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.chain.borrow_mut().tdi = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.chain.borrow_mut().tdi = true;
+        Ok(())
+    }
+}
+
+impl OutputPin for SimTck {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.chain.borrow_mut().tck = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        let mut chain = self.0.chain.borrow_mut();
+        if !chain.tck {
+            chain.rising_edge();
+        }
+        chain.tck = true;
+        Ok(())
+    }
+}
+
+impl OutputPin for SimTms {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.chain.borrow_mut().tms = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.chain.borrow_mut().tms = true;
+        Ok(())
+    }
+}
+
+impl InputPin for SimTdo {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.0.chain.borrow().tdo())
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
@@ -0,0 +1,310 @@
+use core::convert::Infallible;
+
+use hal::serial;
+use usb_device::class_prelude::*;
+
+use crate::blaster::{BLASTER_READ_SIZE, BLASTER_WRITE_SIZE};
+use crate::class::{BlasterClass, FTDI_MODEM_STA_DUMMY};
+use crate::error::BlasterError;
+
+/// Parity, as set with [`FTDI_SIO_SET_DATA`](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L198)
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum StopBits {
+    One,
+    OneAndHalf,
+    Two,
+}
+
+/// Flow control, as set with [`FTDI_SIO_SET_FLOW_CTRL`](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.h#L277)
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum FlowControl {
+    None,
+    RtsCts,
+    DtrDsr,
+    XonXoff,
+}
+
+/// Line settings requested by the host for the UART
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct LineCoding {
+    pub baud_rate: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+/// 9600 baud 8N1 without flow control, as an FTDI chip starts up
+impl Default for LineCoding {
+    fn default() -> Self {
+        LineCoding {
+            baud_rate: 9600,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+impl LineCoding {
+    /// Decode the baud rate divisor of an FT232BM or later, from the value and the upper byte of the index of a set baud rate request.
+    /// The lower byte of the index holds the interface, as on multi-port chips.
+    /// See [ftdi_232bm_baud_base_to_divisor](https://github.com/torvalds/linux/blob/master/drivers/usb/serial/ftdi_sio.c)
+    pub(crate) fn set_ftdi_baud_rate(&mut self, value: u16, index_high: u16) {
+        /// Eighths for each sub-integer divisor code
+        const EIGHTHS: [u32; 8] = [0, 4, 2, 1, 3, 5, 6, 7];
+        const BAUD_BASE: u32 = 3_000_000;
+
+        let integer = (value & 0x3FFF) as u32;
+        let code = ((value >> 14) | ((index_high & 0x01) << 2)) as usize;
+        self.baud_rate = match (integer, code) {
+            (0, 0) => BAUD_BASE,
+            (1, 0) => BAUD_BASE * 2 / 3,
+            _ => BAUD_BASE * 8 / (integer * 8 + EIGHTHS[code]),
+        };
+    }
+
+    /// Decode the data bits, parity and stop bits of a set data characteristics request.
+    /// Returns false for reserved parity or stop bit values.
+    pub(crate) fn set_ftdi_data(&mut self, value: u16) -> bool {
+        let parity = match (value >> 8) & 0x07 {
+            0 => Parity::None,
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            3 => Parity::Mark,
+            4 => Parity::Space,
+            _ => return false,
+        };
+        let stop_bits = match (value >> 11) & 0x07 {
+            0 => StopBits::One,
+            1 => StopBits::OneAndHalf,
+            2 => StopBits::Two,
+            _ => return false,
+        };
+        self.data_bits = value as u8;
+        self.parity = parity;
+        self.stop_bits = stop_bits;
+        true
+    }
+
+    /// Decode the handshake in the upper byte of the index of a set flow control request
+    pub(crate) fn set_ftdi_flow_control(&mut self, index: u16) {
+        self.flow_control = match index >> 8 {
+            0x01 => FlowControl::RtsCts,
+            0x02 => FlowControl::DtrDsr,
+            0x04 => FlowControl::XonXoff,
+            _ => FlowControl::None,
+        };
+    }
+}
+
+/// Applies line settings from the host to a serial peripheral.
+/// The orphan rule keeps an application from implementing it for a serial type of its HAL,
+/// so wrap that type in a newtype that forwards `serial::Read` and `serial::Write` and implement it there,
+/// as the Arduino MKR Vidor 4000 example does. Keep the default to accept all settings.
+pub trait UartConfig {
+    /// Reconfigure the peripheral, returning false to reject settings it does not support
+    fn set_line_coding(&mut self, _coding: &LineCoding) -> bool {
+        true
+    }
+}
+
+/// A UART bridged to the host. Use `()` if the board does not have one.
+/// This does not depend on the transport, so it can also be pumped into a CDC-ACM class in a composite device.
+pub trait UartBridge {
+    type Error;
+
+    /// Whether there is a UART at all
+    fn is_attached(&self) -> bool {
+        true
+    }
+    /// Apply line settings from the host, returning false if they are not supported
+    fn set_line_coding(&mut self, coding: &LineCoding) -> bool;
+    /// Take a byte received from the target, if there is one. A byte received with an error is dropped and the error returned.
+    fn read(&mut self) -> Result<Option<u8>, Self::Error>;
+    /// Send a byte to the target, returning false if the UART is busy. A byte that fails to send is dropped and the error returned.
+    fn write(&mut self, byte: u8) -> Result<bool, Self::Error>;
+}
+
+impl UartBridge for () {
+    type Error = Infallible;
+
+    fn is_attached(&self) -> bool {
+        false
+    }
+
+    fn set_line_coding(&mut self, _coding: &LineCoding) -> bool {
+        true
+    }
+
+    fn read(&mut self) -> Result<Option<u8>, Infallible> {
+        Ok(None)
+    }
+
+    fn write(&mut self, _byte: u8) -> Result<bool, Infallible> {
+        Ok(false)
+    }
+}
+
+/// An error reported by the serial peripheral of a [`Uart`]
+#[derive(Debug)]
+pub enum UartError<R, W> {
+    /// Reading a byte failed, e.g. with a framing, parity or overrun error
+    Read(R),
+    /// Writing a byte failed
+    Write(W),
+}
+
+/// An `embedded_hal::serial` peripheral bridged to the host
+pub struct Uart<S> {
+    serial: S,
+}
+
+impl<S> Uart<S> {
+    pub fn new(serial: S) -> Self {
+        Uart { serial }
+    }
+
+    /// Release the serial peripheral
+    pub fn free(self) -> S {
+        self.serial
+    }
+}
+
+impl<S: serial::Read<u8> + serial::Write<u8> + UartConfig> UartBridge for Uart<S> {
+    type Error = UartError<<S as serial::Read<u8>>::Error, <S as serial::Write<u8>>::Error>;
+
+    fn set_line_coding(&mut self, coding: &LineCoding) -> bool {
+        self.serial.set_line_coding(coding)
+    }
+
+    fn read(&mut self) -> Result<Option<u8>, Self::Error> {
+        match self.serial.read() {
+            Ok(byte) => Ok(Some(byte)),
+            Err(nb::Error::WouldBlock) => Ok(None),
+            Err(nb::Error::Other(err)) => Err(UartError::Read(err)),
+        }
+    }
+
+    fn write(&mut self, byte: u8) -> Result<bool, Self::Error> {
+        match serial::Write::write(&mut self.serial, byte) {
+            Ok(()) => Ok(true),
+            Err(nb::Error::WouldBlock) => Ok(false),
+            Err(nb::Error::Other(err)) => Err(UartError::Write(err)),
+        }
+    }
+}
+
+/// Carries UART data over the second pair of FT245 endpoints
+pub(crate) struct UartChannel<U> {
+    pub(crate) uart: U,
+    /// The line settings last accepted by the UART
+    pub(crate) line_coding: LineCoding,
+    /// Bytes from the host waiting for the UART
+    recv_buffer: [u8; BLASTER_WRITE_SIZE],
+    recv_len: usize,
+    /// Bytes from the UART waiting for the host, after the modem status
+    send_buffer: [u8; BLASTER_READ_SIZE],
+    send_len: usize,
+}
+
+impl<U: UartBridge> UartChannel<U> {
+    pub(crate) fn new(uart: U) -> Self {
+        let mut send_buffer = [0u8; BLASTER_READ_SIZE];
+        send_buffer[..2].copy_from_slice(&FTDI_MODEM_STA_DUMMY);
+        UartChannel {
+            uart,
+            line_coding: LineCoding::default(),
+            recv_buffer: [0u8; BLASTER_WRITE_SIZE],
+            recv_len: 0,
+            send_buffer,
+            send_len: 0,
+        }
+    }
+
+    /// Apply line settings from the host to the UART. Returns false if it rejects them.
+    pub(crate) fn set_line_coding(&mut self, coding: LineCoding) -> bool {
+        if !self.uart.set_line_coding(&coding) {
+            return false;
+        }
+        self.line_coding = coding;
+        true
+    }
+
+    /// Move data between the endpoints and the UART, as far as both sides allow.
+    /// A UART error stops the transfer in that direction until the next call, once the bytes before it have gone out.
+    pub(crate) fn bridge<B: UsbBus, E>(
+        &mut self,
+        class: &mut BlasterClass<B>,
+    ) -> Result<(), BlasterError<E>> {
+        if !self.uart.is_attached() {
+            return Ok(());
+        }
+        if self.recv_len == 0 {
+            match class.read_uart(&mut self.recv_buffer) {
+                Ok(amount) => self.recv_len = amount,
+                Err(UsbError::WouldBlock) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let mut failed = false;
+        let mut written = 0;
+        while written < self.recv_len {
+            match self.uart.write(self.recv_buffer[written]) {
+                Ok(true) => written += 1,
+                Ok(false) => break,
+                Err(_) => {
+                    written += 1;
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        self.recv_buffer.copy_within(written..self.recv_len, 0);
+        self.recv_len -= written;
+
+        while self.send_len < self.send_buffer.len() - 2 {
+            match self.uart.read() {
+                Ok(Some(byte)) => {
+                    self.send_buffer[2 + self.send_len] = byte;
+                    self.send_len += 1;
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    failed = true;
+                    break;
+                }
+            }
+        }
+        if self.send_len != 0 {
+            match class.write_uart(&self.send_buffer[..self.send_len + 2]) {
+                // Packets are small enough to always go out whole
+                Ok(_) => self.send_len = 0,
+                Err(UsbError::WouldBlock) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if failed {
+            return Err(BlasterError::Uart);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn purge_rx(&mut self) {
+        self.recv_len = 0;
+    }
+
+    pub(crate) fn purge_tx(&mut self) {
+        self.send_len = 0;
+    }
+}
@@ -0,0 +1,287 @@
+use hal::digital::v2::{InputPin, OutputPin};
+
+use crate::delay::TckDelay;
+use crate::error::BlasterError;
+use crate::pins::{Driven, Polarity, TristatePin};
+use crate::port::Port;
+
+/// Longest TMS or TDI vector accepted by `shift:`, in bytes. Reported by `getinfo:` so the host splits longer shifts.
+pub const XVC_MAX_VECTOR_LEN: usize = 128;
+
+// [XVC 1.0 commands](https://github.com/Xilinx/XilinxVirtualCable)
+const XVC_GETINFO: &[u8] = b"getinfo:";
+const XVC_SETTCK: &[u8] = b"settck:";
+const XVC_SHIFT: &[u8] = b"shift:";
+/// Reply to `getinfo:`, and the number of bytes used
+const XVC_INFO: ([u8; 32], usize) = info_reply(XVC_MAX_VECTOR_LEN);
+
+/// `shift:`, the number of bits, and the TMS and TDI vectors
+const XVC_RECV_SIZE: usize = 10 + 2 * XVC_MAX_VECTOR_LEN;
+
+/// A Xilinx Virtual Cable (XVC) 1.0 server for Vivado's hardware server, on the same JTAG pins as the [`Blaster`](crate::Blaster).
+/// It does not depend on a transport: bytes from the host go in with [`Xvc::receive`], and the replies come out of [`Xvc::transmit`].
+pub struct Xvc<
+    E,
+    TDI: TristatePin<Error = E>,
+    TCK: TristatePin<Error = E>,
+    TMS: TristatePin<Error = E>,
+    TDO: InputPin<Error = E>,
+    D: TckDelay = (),
+> {
+    port: Port<E, TDI, TCK, TMS, TDO, (), (), D>,
+    /// TCK period last set by the host, in nanoseconds
+    tck_period: u32,
+    recv_buffer: [u8; XVC_RECV_SIZE],
+    recv_len: usize,
+    send_buffer: [u8; XVC_MAX_VECTOR_LEN],
+    send_len: usize,
+}
+
+impl<
+        E,
+        TDI: OutputPin<Error = E>,
+        TCK: OutputPin<Error = E>,
+        TMS: OutputPin<Error = E>,
+        TDO: InputPin<Error = E>,
+    > Xvc<E, Driven<TDI>, Driven<TCK>, Driven<TMS>, TDO>
+{
+    /// Create an XVC server. Takes control of the four JTAG pins.
+    /// TDI, TCK and TMS are always driven; use [`Xvc::new_tristate`] for pins implementing [`TristatePin`].
+    pub fn new(tdi: TDI, tck: TCK, tms: TMS, tdo: TDO) -> Self {
+        Xvc::new_tristate(Driven(tdi), Driven(tck), Driven(tms), tdo)
+    }
+}
+
+impl<
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+    > Xvc<E, TDI, TCK, TMS, TDO>
+{
+    /// Create an XVC server. Takes control of the four JTAG pins, which start out driven.
+    pub fn new_tristate(tdi: TDI, tck: TCK, tms: TMS, tdo: TDO) -> Self {
+        Xvc {
+            port: Port::new(tdi, tck, tms, tdo),
+            tck_period: 1000,
+            recv_buffer: [0u8; XVC_RECV_SIZE],
+            recv_len: 0,
+            send_buffer: [0u8; XVC_MAX_VECTOR_LEN],
+            send_len: 0,
+        }
+    }
+}
+
+impl<
+        E,
+        TDI: TristatePin<Error = E>,
+        TCK: TristatePin<Error = E>,
+        TMS: TristatePin<Error = E>,
+        TDO: InputPin<Error = E>,
+        D: TckDelay,
+    > Xvc<E, TDI, TCK, TMS, TDO, D>
+{
+    /// Attach a delay, so that TCK stays under the frequency set by the host with `settck:`.
+    pub fn with_delay<D2: TckDelay>(self, delay: D2) -> Xvc<E, TDI, TCK, TMS, TDO, D2> {
+        self.map_port(|port| port.with_delay(delay))
+    }
+
+    /// Mark the lines that pass through inverting buffers. The JTAG state is tracked on the logic levels.
+    pub fn with_polarity(mut self, polarity: Polarity) -> Self {
+        self.port.set_polarity(polarity);
+        self
+    }
+
+    /// Swap the port for one built from it, keeping everything else
+    fn map_port<D2: TckDelay>(
+        self,
+        f: impl FnOnce(
+            Port<E, TDI, TCK, TMS, TDO, (), (), D>,
+        ) -> Port<E, TDI, TCK, TMS, TDO, (), (), D2>,
+    ) -> Xvc<E, TDI, TCK, TMS, TDO, D2> {
+        Xvc {
+            port: f(self.port),
+            tck_period: self.tck_period,
+            recv_buffer: self.recv_buffer,
+            recv_len: self.recv_len,
+            send_buffer: self.send_buffer,
+            send_len: self.send_len,
+        }
+    }
+
+    /// The TCK period set by the host with `settck:`, in nanoseconds
+    pub fn tck_period(&self) -> u32 {
+        self.tck_period
+    }
+
+    /// Copy bytes from the host into the internal read buffer. Returns how many fit.
+    pub fn receive(&mut self, data: &[u8]) -> usize {
+        let amount = data.len().min(self.recv_buffer.len() - self.recv_len);
+        self.recv_buffer[self.recv_len..self.recv_len + amount].copy_from_slice(&data[..amount]);
+        self.recv_len += amount;
+        amount
+    }
+
+    /// Copy replies for the host from the internal write buffer. Returns how many bytes were copied.
+    pub fn transmit(&mut self, data: &mut [u8]) -> usize {
+        let amount = data.len().min(self.send_len);
+        data[..amount].copy_from_slice(&self.send_buffer[..amount]);
+        self.send_buffer.copy_within(amount..self.send_len, 0);
+        self.send_len -= amount;
+        amount
+    }
+
+    /// Runs the complete commands in the internal read buffer until either none are left or the reply to the next one does not fit in the internal write buffer.
+    /// An unknown command or an oversized shift cannot be skipped over, so the read buffer is dropped and [`BlasterError::InvalidRequest`] returned with the first byte.
+    pub fn handle(&mut self) -> Result<(), BlasterError<E>> {
+        loop {
+            let buffer = &self.recv_buffer[..self.recv_len];
+            let free = self.send_buffer.len() - self.send_len;
+            if buffer.starts_with(XVC_GETINFO) {
+                let info = &XVC_INFO.0[..XVC_INFO.1];
+                if free < info.len() {
+                    return Ok(());
+                }
+                self.send_buffer[self.send_len..self.send_len + info.len()].copy_from_slice(info);
+                self.send_len += info.len();
+                self.consume(XVC_GETINFO.len());
+            } else if buffer.starts_with(XVC_SETTCK) {
+                let size = XVC_SETTCK.len() + 4;
+                if buffer.len() < size || free < 4 {
+                    return Ok(());
+                }
+                let mut period = [0u8; 4];
+                period.copy_from_slice(&buffer[XVC_SETTCK.len()..size]);
+                // With a delay attached TCK is held for at least half the period on each edge, so the period asked for is the one reported back
+                self.tck_period = u32::from_le_bytes(period);
+                let hz = match self.tck_period {
+                    0 => 0,
+                    period => (1_000_000_000 / period).max(1),
+                };
+                self.port.set_tck_frequency(hz);
+                self.send_buffer[self.send_len..self.send_len + 4].copy_from_slice(&period);
+                self.send_len += 4;
+                self.consume(size);
+            } else if buffer.starts_with(XVC_SHIFT) {
+                if buffer.len() < XVC_SHIFT.len() + 4 {
+                    return Ok(());
+                }
+                let mut bits = [0u8; 4];
+                bits.copy_from_slice(&buffer[XVC_SHIFT.len()..XVC_SHIFT.len() + 4]);
+                let bits = u32::from_le_bytes(bits) as usize;
+                let bytes = bits.div_ceil(8);
+                if bytes > XVC_MAX_VECTOR_LEN {
+                    return Err(self.invalid());
+                }
+                let size = XVC_SHIFT.len() + 4 + 2 * bytes;
+                if buffer.len() < size || free < bytes {
+                    return Ok(());
+                }
+                self.shift(bits, bytes)?;
+                self.consume(size);
+            } else if buffer.is_empty()
+                || XVC_GETINFO.starts_with(buffer)
+                || XVC_SETTCK.starts_with(buffer)
+                || XVC_SHIFT.starts_with(buffer)
+            {
+                // Wait for the rest of the command name
+                return Ok(());
+            } else {
+                return Err(self.invalid());
+            }
+        }
+    }
+
+    /// Clock the TMS and TDI vectors of the `shift:` at the front of the read buffer out, and the TDO vector into the write buffer
+    fn shift(&mut self, bits: usize, bytes: usize) -> Result<(), BlasterError<E>> {
+        let tms_offset = XVC_SHIFT.len() + 4;
+        let tdi_offset = tms_offset + bytes;
+        let tdo = &mut self.send_buffer[self.send_len..self.send_len + bytes];
+        for byte in tdo.iter_mut() {
+            *byte = 0;
+        }
+        for n in 0..bits {
+            let tms = self.recv_buffer[tms_offset + n / 8] >> (n % 8) & 1 != 0;
+            let tdi = self.recv_buffer[tdi_offset + n / 8] >> (n % 8) & 1 != 0;
+            if self.port.clock_tms(tms, tdi)? {
+                self.send_buffer[self.send_len + n / 8] |= 1 << (n % 8);
+            }
+        }
+        self.send_len += bytes;
+        Ok(())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.recv_buffer.copy_within(amount..self.recv_len, 0);
+        self.recv_len -= amount;
+    }
+
+    fn invalid(&mut self) -> BlasterError<E> {
+        let first = self.recv_buffer[0];
+        self.recv_len = 0;
+        BlasterError::InvalidRequest(first)
+    }
+
+    /// Serve one host connection on a byte stream, such as a TCP socket, until the host closes it.
+    #[cfg(feature = "std")]
+    pub fn serve<S: std::io::Read + std::io::Write>(
+        &mut self,
+        stream: &mut S,
+    ) -> std::io::Result<()> {
+        use std::io::{Error, ErrorKind};
+
+        let mut chunk = [0u8; XVC_RECV_SIZE];
+        loop {
+            // Run and reply to the buffered commands until neither makes progress, leaving room to read into
+            loop {
+                let pending = self.recv_len;
+                self.handle().map_err(|err| match err {
+                    BlasterError::InvalidRequest(_) => {
+                        Error::new(ErrorKind::InvalidData, "unknown XVC command")
+                    }
+                    _ => Error::other("JTAG pin error"),
+                })?;
+                let mut reply = [0u8; XVC_MAX_VECTOR_LEN];
+                let len = self.transmit(&mut reply);
+                stream.write_all(&reply[..len])?;
+                if len == 0 && self.recv_len == pending {
+                    break;
+                }
+            }
+            let space = XVC_RECV_SIZE - self.recv_len;
+            if space == 0 {
+                // Every command fits in the read buffer, so this is never reached
+                return Err(Error::new(ErrorKind::InvalidData, "XVC command too long"));
+            }
+            // The buffer is not empty, so reading nothing means the host has closed the stream
+            let amount = stream.read(&mut chunk[..space])?;
+            if amount == 0 {
+                return Ok(());
+            }
+            self.receive(&chunk[..amount]);
+        }
+    }
+}
+
+/// Build the reply to `getinfo:` for vectors of up to `max_len` bytes, which is given in decimal
+const fn info_reply(max_len: usize) -> ([u8; 32], usize) {
+    const PREFIX: &[u8] = b"xvcServer_v1.0:";
+    let mut info = [0u8; 32];
+    let mut len = 0;
+    while len < PREFIX.len() {
+        info[len] = PREFIX[len];
+        len += 1;
+    }
+    let mut place = 1;
+    while max_len / place >= 10 {
+        place *= 10;
+    }
+    while place > 0 {
+        info[len] = b'0' + (max_len / place % 10) as u8;
+        len += 1;
+        place /= 10;
+    }
+    info[len] = b'\n';
+    (info, len + 1)
+}
@@ -0,0 +1,994 @@
+//! Drives a `Blaster` through a `MockBus`, with a simulated JTAG chain on its pins.
+
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use usb_device::bus::UsbBusAllocator;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::prelude::*;
+use usb_device::UsbDirection;
+use usbd_blaster::testing::{JtagChain, MockBus, MockHost, TapDevice};
+use usbd_blaster::{
+    ActiveSerial, Blaster, BlasterError, Budget, CycleDelay, Driven, EepromBuilder, EepromStorage,
+    Heartbeat, JtagBackend, MaskedWrite, MillisClock, Mode, PassiveSerial, Polarity, ResetDrive,
+    ResetLine, ResetLines, ReturnClock, ShiftSpi, SpiMux, SpiShift, TristatePin, UartBridge,
+    ALTERA_BLASTER_USB_VID_PID, BLASTER_VEN_REQ_GET_TCK_FREQ, BLASTER_VEN_REQ_RESET,
+    BLASTER_VEN_REQ_SET_TCK_FREQ, EEPROM_SIZE,
+};
+
+/// Bulk OUT endpoint carrying bytes from the host
+const EP_OUT: u8 = 2;
+/// Bulk IN endpoint carrying replies to the host
+const EP_IN: u8 = 1;
+/// The modem status at the start of every IN packet
+const MODEM_STATUS: [u8; 2] = [0x01, 0xC0];
+
+const FTDI_VEN_REQ_RESET: u8 = 0x00;
+const FTDI_VEN_REQ_SET_LAT_TIMER: u8 = 0x09;
+const FTDI_VEN_REQ_GET_LAT_TIMER: u8 = 0x0A;
+const FTDI_VEN_REQ_RD_EEPROM: u8 = 0x90;
+const FTDI_VEN_REQ_WR_EEPROM: u8 = 0x91;
+
+fn vendor_request(
+    direction: UsbDirection,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+) -> Request {
+    Request {
+        direction,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request,
+        value,
+        index,
+        length,
+    }
+}
+
+/// Bit-bang bytes that clock TMS through `tms`, with the outputs enabled
+fn clock_tms(tms: &[bool]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for &high in tms {
+        let tms_bit = if high { 0x02 } else { 0x00 };
+        bytes.push(0x20 | tms_bit);
+        bytes.push(0x21 | tms_bit);
+    }
+    bytes.push(0x20);
+    bytes
+}
+
+/// Bit-bang bytes that go through Test-Logic-Reset to Shift-DR
+fn reset_to_shift_dr() -> Vec<u8> {
+    clock_tms(&[true, true, true, true, true, false, true, false, false])
+}
+
+/// Run everything queued on the bus through the blaster and take the reply
+fn exchange<
+    'a,
+    J: JtagBackend<Error = Infallible>,
+    S: EepromStorage,
+    C: MillisClock,
+    U: UartBridge,
+>(
+    dev: &mut UsbDevice<'a, MockBus>,
+    blaster: &mut Blaster<'a, MockBus, J, S, C, U>,
+    bytes: &[u8],
+) -> Vec<u8> {
+    dev.bus().bulk_out(EP_OUT, bytes);
+    dev.poll(&mut [blaster]);
+    blaster.read().unwrap();
+    blaster.handle().unwrap();
+    blaster.write().unwrap();
+    dev.bus().bulk_in(EP_IN).unwrap()
+}
+
+#[test]
+fn reads_idcodes() {
+    let chain = JtagChain::new(vec![
+        TapDevice::new(0x1234_5679, 10, 0x006),
+        TapDevice::new(0x0ABC_DEF1, 4, 0x1),
+    ]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    let mut bytes = reset_to_shift_dr();
+    // Shift and read 8 bytes
+    bytes.push(0xC8);
+    bytes.extend_from_slice(&[0; 8]);
+    let reply = exchange(&mut dev, &mut blaster, &bytes);
+
+    // The device nearest TDO comes out first
+    let mut expected = MODEM_STATUS.to_vec();
+    expected.extend_from_slice(&0x0ABC_DEF1u32.to_le_bytes());
+    expected.extend_from_slice(&0x1234_5679u32.to_le_bytes());
+    assert_eq!(reply, expected);
+    assert_eq!(chain.rising_edges(), 9 + 64);
+}
+
+#[test]
+fn every_packet_starts_with_modem_status() {
+    let chain = JtagChain::new(vec![TapDevice::bypass_only(4)]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // Nothing to send but the status
+    blaster.write().unwrap();
+    assert_eq!(dev.bus().bulk_in(EP_IN).unwrap(), MODEM_STATUS);
+
+    // Three bit-bang reads, with TDO pulled up outside of Shift-DR and Shift-IR
+    let reply = exchange(&mut dev, &mut blaster, &[0x60, 0x60, 0x60]);
+    assert_eq!(reply, [MODEM_STATUS[0], MODEM_STATUS[1], 0x01, 0x01, 0x01]);
+
+    blaster.write().unwrap();
+    assert_eq!(dev.bus().bulk_in(EP_IN).unwrap(), MODEM_STATUS);
+}
+
+#[test]
+fn resends_status_after_short_write() {
+    let chain = JtagChain::new(vec![TapDevice::bypass_only(4)]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    dev.bus().bulk_out(EP_OUT, &[0x60, 0x60, 0x60]);
+    dev.poll(&mut [&mut blaster]);
+    blaster.read().unwrap();
+    blaster.handle().unwrap();
+
+    // Half of the status goes out: the host strips it and gets no data
+    dev.bus().accept_short(EP_IN, 1);
+    assert_eq!(blaster.write().unwrap(), 1);
+    assert_eq!(dev.bus().bulk_in(EP_IN).unwrap(), MODEM_STATUS[..1]);
+    dev.poll(&mut [&mut blaster]);
+    // The status and one data byte go out
+    dev.bus().accept_short(EP_IN, 3);
+    assert_eq!(blaster.write().unwrap(), 3);
+    assert_eq!(
+        dev.bus().bulk_in(EP_IN).unwrap(),
+        [MODEM_STATUS[0], MODEM_STATUS[1], 0x01]
+    );
+    dev.poll(&mut [&mut blaster]);
+    // The rest follows a fresh status
+    blaster.write().unwrap();
+    assert_eq!(
+        dev.bus().bulk_in(EP_IN).unwrap(),
+        [MODEM_STATUS[0], MODEM_STATUS[1], 0x01, 0x01]
+    );
+}
+
+#[test]
+fn reads_eeprom() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_serial_number("ABCD1234");
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    let mut image = Vec::new();
+    for word in 0..64 {
+        let req = vendor_request(UsbDirection::In, FTDI_VEN_REQ_RD_EEPROM, 0, word, 2);
+        image.extend(dev.control_in(&mut [&mut blaster], req).unwrap());
+    }
+    assert_eq!(image[..], blaster.eeprom()[..]);
+}
+
+#[test]
+fn purges_received_bytes() {
+    let chain = JtagChain::new(vec![TapDevice::bypass_only(4)]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    dev.bus().bulk_out(EP_OUT, &[0x60, 0x60]);
+    dev.poll(&mut [&mut blaster]);
+    blaster.read().unwrap();
+    let purge_rx = vendor_request(UsbDirection::Out, FTDI_VEN_REQ_RESET, 1, 0, 0);
+    dev.control_out(&mut [&mut blaster], purge_rx, &[]).unwrap();
+    blaster.handle().unwrap();
+    blaster.write().unwrap();
+    assert_eq!(dev.bus().bulk_in(EP_IN).unwrap(), MODEM_STATUS);
+}
+
+#[test]
+fn reports_default_latency_timer() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // 16 ms, as on an FTDI chip
+    let req = vendor_request(UsbDirection::In, FTDI_VEN_REQ_GET_LAT_TIMER, 0, 0, 1);
+    assert_eq!(dev.control_in(&mut [&mut blaster], req).unwrap(), [16]);
+    assert_eq!(blaster.latency_timer(), 16);
+}
+
+#[test]
+fn host_sets_tck_frequency() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster =
+        Blaster::new(&alloc, tdi, tck, tms, tdo).with_delay(CycleDelay::new(48_000_000, |_| {}));
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // 6 MHz, with the high half in the index
+    let hz = 6_000_000u32;
+    let set = vendor_request(
+        UsbDirection::Out,
+        BLASTER_VEN_REQ_SET_TCK_FREQ,
+        hz as u16,
+        (hz >> 16) as u16,
+        0,
+    );
+    dev.control_out(&mut [&mut blaster], set, &[]).unwrap();
+    assert_eq!(blaster.tck_frequency(), hz);
+
+    let get = vendor_request(UsbDirection::In, BLASTER_VEN_REQ_GET_TCK_FREQ, 0, 0, 4);
+    assert_eq!(
+        dev.control_in(&mut [&mut blaster], get).unwrap(),
+        hz.to_le_bytes()
+    );
+}
+
+/// Storage that keeps the committed image where the test can see it
+struct Flash(Rc<RefCell<Option<[u8; EEPROM_SIZE]>>>);
+
+impl EepromStorage for Flash {
+    type Error = ();
+
+    fn load(&mut self, image: &mut [u8; EEPROM_SIZE]) -> Result<bool, ()> {
+        match *self.0.borrow() {
+            Some(stored) => {
+                *image = stored;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn store(&mut self, image: &[u8; EEPROM_SIZE]) -> Result<(), ()> {
+        *self.0.borrow_mut() = Some(*image);
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), ()> {
+        *self.0.borrow_mut() = None;
+        Ok(())
+    }
+}
+
+#[test]
+fn serves_written_eeprom_only_once_committed() {
+    let flash = Rc::new(RefCell::new(None));
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster =
+        Blaster::new(&alloc, tdi, tck, tms, tdo).with_eeprom_storage(Flash(flash.clone()));
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+    let original = *blaster.eeprom();
+
+    let image = EepromBuilder::new().product("Branded").build();
+    let words: Vec<u16> = image
+        .chunks(2)
+        .map(|word| u16::from_le_bytes([word[0], word[1]]))
+        .collect();
+    let mut write = |word: usize, value: u16| {
+        let req = vendor_request(
+            UsbDirection::Out,
+            FTDI_VEN_REQ_WR_EEPROM,
+            value,
+            word as u16,
+            0,
+        );
+        dev.control_out(&mut [&mut blaster], req, &[])
+    };
+    for (word, &value) in words.iter().enumerate().take(63) {
+        write(word, value).unwrap();
+    }
+    // A bad checksum is rejected, and the host keeps reading the old image
+    assert!(write(63, !words[63]).is_err());
+    assert!(flash.borrow().is_none());
+    assert_eq!(blaster.eeprom()[..], original[..]);
+    let req = vendor_request(UsbDirection::In, FTDI_VEN_REQ_RD_EEPROM, 0, 8, 2);
+    assert_eq!(
+        dev.control_in(&mut [&mut blaster], req).unwrap(),
+        original[16..18]
+    );
+
+    let req = vendor_request(UsbDirection::Out, FTDI_VEN_REQ_WR_EEPROM, words[63], 63, 0);
+    dev.control_out(&mut [&mut blaster], req, &[]).unwrap();
+    assert_eq!(flash.borrow().unwrap()[..], image[..]);
+    assert_eq!(blaster.eeprom()[..], image[..]);
+}
+
+/// An SPI peripheral that counts the transfers handed to it, and leaves the bytes as they are
+struct CountingSpi(Rc<Cell<usize>>);
+
+impl ShiftSpi<Infallible> for CountingSpi {
+    fn attach(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn shift(&mut self, _data: &mut [u8], _read: bool) -> Result<(), BlasterError<Infallible>> {
+        self.0.set(self.0.get() + 1);
+        Ok(())
+    }
+}
+
+/// A return clock that follows TCK by the second sample
+struct Toggling(Cell<bool>);
+
+impl ReturnClock<Infallible> for Toggling {
+    fn rtck(&self) -> Result<Option<bool>, Infallible> {
+        self.0.set(!self.0.get());
+        Ok(Some(self.0.get()))
+    }
+}
+
+#[test]
+fn shifts_on_spi_only_when_it_can() {
+    let transfers = Rc::new(Cell::new(0));
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster =
+        Blaster::new(&alloc, tdi, tck, tms, tdo).with_spi(CountingSpi(transfers.clone()));
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // The outputs are still disabled after a reset, so the byte is bit-banged
+    dev.bus().bulk_out(EP_OUT, &[0x81, 0xA5]);
+    blaster.poll(&mut dev).unwrap();
+    assert_eq!((transfers.get(), chain.rising_edges()), (0, 8));
+
+    dev.bus().bulk_out(EP_OUT, &[0x20, 0x81, 0xA5]);
+    blaster.poll(&mut dev).unwrap();
+    assert_eq!((transfers.get(), chain.rising_edges()), (1, 8));
+
+    // With RTCK attached every edge has to wait for it
+    let transfers = Rc::new(Cell::new(0));
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo)
+        .with_spi(CountingSpi(transfers.clone()))
+        .with_return_clock(Toggling(Cell::new(false)));
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    dev.bus().bulk_out(EP_OUT, &[0x20, 0x81, 0xA5]);
+    blaster.poll(&mut dev).unwrap();
+    assert_eq!((transfers.get(), chain.rising_edges()), (0, 8));
+}
+
+/// A write-only SPI peripheral that keeps what it is sent, or fails
+struct RecordingSpi {
+    written: Vec<u8>,
+    fails: bool,
+}
+
+impl Write<u8> for RecordingSpi {
+    type Error = ();
+
+    fn write(&mut self, words: &[u8]) -> Result<(), ()> {
+        if self.fails {
+            return Err(());
+        }
+        self.written.extend_from_slice(words);
+        Ok(())
+    }
+}
+
+impl Transfer<u8> for RecordingSpi {
+    type Error = ();
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], ()> {
+        self.write(words)?;
+        Ok(words)
+    }
+}
+
+/// Pins that are always routed to the peripheral
+struct NoMux;
+
+impl SpiMux<Infallible> for NoMux {
+    fn to_spi(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn to_gpio(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+#[test]
+fn shifts_msb_first_spi_without_touching_bytes() {
+    let mut spi = SpiShift {
+        spi: RecordingSpi {
+            written: Vec::new(),
+            fails: false,
+        },
+        mux: NoMux,
+        msb_first: true,
+    };
+    let mut data = [0x01, 0x80, 0x0F];
+    ShiftSpi::<Infallible>::shift(&mut spi, &mut data, false).unwrap();
+    assert_eq!(data, [0x01, 0x80, 0x0F]);
+    assert_eq!(spi.spi.written, [0x80, 0x01, 0xF0]);
+
+    spi.spi.fails = true;
+    assert!(matches!(
+        ShiftSpi::<Infallible>::shift(&mut spi, &mut data, false),
+        Err(BlasterError::Spi)
+    ));
+}
+
+/// A pin whose level the test can set and see
+#[derive(Clone, Default)]
+struct Line(Rc<Cell<bool>>);
+
+impl OutputPin for Line {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.set(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.set(true);
+        Ok(())
+    }
+}
+
+impl InputPin for Line {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(self.0.get())
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(!self.0.get())
+    }
+}
+
+#[test]
+fn programs_configuration_device_on_active_serial() {
+    let (nce, ncs, dataout) = (Line::default(), Line::default(), Line::default());
+    let chain = JtagChain::new(vec![TapDevice::bypass_only(4)]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_active_serial(ActiveSerial {
+        nce: nce.clone(),
+        ncs: ncs.clone(),
+        dataout: dataout.clone(),
+    });
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // nCE and nCS follow the host, and DATAOUT is read back next to TDO
+    dataout.0.set(true);
+    let reply = exchange(&mut dev, &mut blaster, &[0x6C]);
+    assert_eq!(reply[2..], [0x03]);
+    assert!(nce.0.get() && ncs.0.get());
+
+    // While the device is selected, shift mode reads DATAOUT instead of TDO
+    dataout.0.set(false);
+    let reply = exchange(&mut dev, &mut blaster, &[0x60, 0xC1, 0xAA]);
+    assert_eq!(reply[2..], [0x01, 0x00]);
+    assert!(!nce.0.get() && !ncs.0.get());
+}
+
+#[test]
+fn configures_fpga_on_passive_serial() {
+    let (nstatus, conf_done) = (Line::default(), Line::default());
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_passive_serial(PassiveSerial {
+        nstatus: nstatus.clone(),
+        conf_done: conf_done.clone(),
+    });
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+    assert_eq!(blaster.mode(), Mode::PassiveSerial);
+
+    // CONF_DONE and nSTATUS are read back in place of TDO and DATAOUT
+    nstatus.0.set(true);
+    let reply = exchange(&mut dev, &mut blaster, &[0x62]);
+    assert_eq!(reply[2..], [0x02]);
+
+    // The bitstream is clocked out on DCLK, and shift mode reads CONF_DONE
+    let edges = chain.rising_edges();
+    exchange(&mut dev, &mut blaster, &[0x81, 0xA5]);
+    assert_eq!(chain.rising_edges() - edges, 8);
+    conf_done.0.set(true);
+    let reply = exchange(&mut dev, &mut blaster, &[0xC1, 0x00]);
+    assert_eq!(reply[2..], [0xFF]);
+}
+
+/// An output that records whether it is driven or left floating
+struct Tristate<P> {
+    pin: P,
+    driven: Rc<Cell<bool>>,
+}
+
+impl<P: OutputPin> OutputPin for Tristate<P> {
+    type Error = P::Error;
+
+    fn set_low(&mut self) -> Result<(), P::Error> {
+        self.pin.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), P::Error> {
+        self.pin.set_high()
+    }
+}
+
+impl<P: OutputPin> TristatePin for Tristate<P> {
+    fn set_floating(&mut self) -> Result<(), P::Error> {
+        self.driven.set(false);
+        Ok(())
+    }
+
+    fn set_driven(&mut self) -> Result<(), P::Error> {
+        self.driven.set(true);
+        Ok(())
+    }
+}
+
+#[test]
+fn floats_lines_while_outputs_are_disabled() {
+    let driven = Rc::new(Cell::new(true));
+    let chain = JtagChain::new(vec![TapDevice::bypass_only(4)]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new_tristate(
+        &alloc,
+        Tristate {
+            pin: tdi,
+            driven: driven.clone(),
+        },
+        Tristate {
+            pin: tck,
+            driven: driven.clone(),
+        },
+        Tristate {
+            pin: tms,
+            driven: driven.clone(),
+        },
+        tdo,
+    );
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // Released on a USB reset, until the host sets OE
+    assert!(!driven.get());
+    exchange(&mut dev, &mut blaster, &[0x20]);
+    assert!(driven.get());
+    exchange(&mut dev, &mut blaster, &[0x00]);
+    assert!(!driven.get());
+}
+
+/// The time `latency_clock` reports, in milliseconds
+static LATENCY_NOW: AtomicU32 = AtomicU32::new(0);
+
+fn latency_clock() -> u32 {
+    LATENCY_NOW.load(Ordering::SeqCst)
+}
+
+#[test]
+fn holds_partial_packet_until_latency_timer_expires() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster =
+        Blaster::new(&alloc, tdi, tck, tms, tdo).with_clock(latency_clock as fn() -> u32);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // A zero timer is rejected, as on an FTDI chip
+    let req = vendor_request(UsbDirection::Out, FTDI_VEN_REQ_SET_LAT_TIMER, 0, 0, 0);
+    assert!(dev.control_out(&mut [&mut blaster], req, &[]).is_err());
+    let req = vendor_request(UsbDirection::Out, FTDI_VEN_REQ_SET_LAT_TIMER, 5, 0, 0);
+    dev.control_out(&mut [&mut blaster], req, &[]).unwrap();
+    assert_eq!(blaster.latency_timer(), 5);
+    blaster.write().unwrap();
+    assert_eq!(dev.bus().bulk_in(EP_IN).unwrap(), MODEM_STATUS);
+
+    // One read byte waits for more until the timer runs out
+    dev.bus().bulk_out(EP_OUT, &[0x40]);
+    dev.poll(&mut [&mut blaster]);
+    blaster.read().unwrap();
+    blaster.handle().unwrap();
+    assert!(blaster.write().is_err());
+    LATENCY_NOW.store(4, Ordering::SeqCst);
+    assert!(blaster.write().is_err());
+    LATENCY_NOW.store(5, Ordering::SeqCst);
+    assert_eq!(blaster.write().unwrap(), 3);
+    assert_eq!(dev.bus().bulk_in(EP_IN).unwrap().len(), 3);
+}
+
+#[test]
+fn sends_status_only_on_demand() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_heartbeat(Heartbeat::OnDemand);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // Nothing to send without read bytes
+    assert!(blaster.write().is_err());
+    assert_eq!(
+        exchange(&mut dev, &mut blaster, &[0x40]),
+        [MODEM_STATUS[0], MODEM_STATUS[1], 0x00]
+    );
+}
+
+/// The time `heartbeat_clock` reports, in milliseconds
+static HEARTBEAT_NOW: AtomicU32 = AtomicU32::new(0);
+
+fn heartbeat_clock() -> u32 {
+    HEARTBEAT_NOW.load(Ordering::SeqCst)
+}
+
+#[test]
+fn sends_status_once_per_latency_timer() {
+    HEARTBEAT_NOW.store(100, Ordering::SeqCst);
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo)
+        .with_clock(heartbeat_clock as fn() -> u32)
+        .with_heartbeat(Heartbeat::LatencyTimer);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    assert!(blaster.write().is_err());
+    // The default 16 ms timer runs out
+    HEARTBEAT_NOW.store(116, Ordering::SeqCst);
+    assert_eq!(blaster.write().unwrap(), 2);
+    assert_eq!(dev.bus().bulk_in(EP_IN).unwrap(), MODEM_STATUS);
+    assert!(blaster.write().is_err());
+}
+
+#[test]
+fn keeps_first_control_error_until_taken() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+    assert!(blaster.take_error().is_none());
+
+    // No reset lines are connected, and a zero latency timer is out of range
+    let req = vendor_request(UsbDirection::Out, BLASTER_VEN_REQ_RESET, 1, 2, 0);
+    assert!(dev.control_out(&mut [&mut blaster], req, &[]).is_err());
+    let req = vendor_request(UsbDirection::Out, FTDI_VEN_REQ_SET_LAT_TIMER, 0, 0, 0);
+    assert!(dev.control_out(&mut [&mut blaster], req, &[]).is_err());
+    assert!(matches!(
+        blaster.take_error(),
+        Some(BlasterError::InvalidRequest(BLASTER_VEN_REQ_RESET))
+    ));
+    assert!(blaster.take_error().is_none());
+}
+
+#[test]
+fn leaves_bytes_over_budget_for_next_poll() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_budget(Budget::Bytes(2));
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // Two of the three read bytes fit in the budget
+    dev.bus().bulk_out(EP_OUT, &[0x40, 0x40, 0x40]);
+    let summary = blaster.poll(&mut dev).unwrap();
+    assert_eq!(
+        (
+            summary.received,
+            summary.handled,
+            summary.sent,
+            summary.pending
+        ),
+        (3, 2, 4, 1)
+    );
+    assert!(summary.is_busy());
+    assert_eq!(dev.bus().bulk_in(EP_IN).unwrap().len(), 4);
+
+    // The next poll handles the rest
+    let summary = blaster.poll(&mut dev).unwrap();
+    assert_eq!(
+        (
+            summary.received,
+            summary.handled,
+            summary.sent,
+            summary.pending
+        ),
+        (0, 1, 3, 0)
+    );
+    assert!(!summary.is_busy());
+}
+
+/// A pin behind an inverting buffer
+struct Inverted<P>(P);
+
+impl<P: OutputPin> OutputPin for Inverted<P> {
+    type Error = P::Error;
+
+    fn set_low(&mut self) -> Result<(), P::Error> {
+        self.0.set_high()
+    }
+
+    fn set_high(&mut self) -> Result<(), P::Error> {
+        self.0.set_low()
+    }
+}
+
+impl<P: InputPin> InputPin for Inverted<P> {
+    type Error = P::Error;
+
+    fn is_high(&self) -> Result<bool, P::Error> {
+        self.0.is_low()
+    }
+
+    fn is_low(&self) -> Result<bool, P::Error> {
+        self.0.is_high()
+    }
+}
+
+#[test]
+fn reads_idcodes_through_inverting_buffers() {
+    let chain = JtagChain::new(vec![TapDevice::new(0x1234_5679, 10, 0x006)]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let polarity = Polarity {
+        tdi: true,
+        tck: true,
+        tms: true,
+        tdo: true,
+        ..Polarity::default()
+    };
+    let mut blaster = Blaster::new(
+        &alloc,
+        Inverted(tdi),
+        Inverted(tck),
+        Inverted(tms),
+        Inverted(tdo),
+    )
+    .with_polarity(polarity);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // The host sends logic levels, as for a blaster without buffers
+    let mut bytes = reset_to_shift_dr();
+    bytes.push(0xC4);
+    bytes.extend_from_slice(&[0; 4]);
+    let reply = exchange(&mut dev, &mut blaster, &bytes);
+
+    let mut expected = MODEM_STATUS.to_vec();
+    expected.extend_from_slice(&0x1234_5679u32.to_le_bytes());
+    assert_eq!(reply, expected);
+    assert_eq!(chain.rising_edges(), 9 + 32);
+}
+
+/// An output that records every level written to it
+struct Levels(Rc<RefCell<Vec<bool>>>);
+
+impl OutputPin for Levels {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().push(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().push(true);
+        Ok(())
+    }
+}
+
+#[test]
+fn pulses_ntrst_for_the_host() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let levels = Rc::new(RefCell::new(Vec::new()));
+    let waits = Rc::new(RefCell::new(Vec::new()));
+    let cycles = waits.clone();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let ntrst = ResetLine {
+        pin: Driven(Levels(levels.clone())),
+        drive: ResetDrive::PushPull,
+    };
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo)
+        .with_reset_pins(ResetLines { ntrst, nsrst: () })
+        .with_delay(CycleDelay::new(1_000_000, move |c| {
+            cycles.borrow_mut().push(c)
+        }))
+        .with_reset_pulse(50, 0);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+    // Released on a USB reset
+    assert_eq!(*levels.borrow(), [true]);
+
+    // Pulse nTRST
+    let req = vendor_request(UsbDirection::Out, BLASTER_VEN_REQ_RESET, 1, 2, 0);
+    dev.control_out(&mut [&mut blaster], req, &[]).unwrap();
+    assert_eq!(*levels.borrow(), [true, false, true]);
+    assert_eq!(*waits.borrow(), [50]);
+
+    // nSRST is not connected
+    let req = vendor_request(UsbDirection::Out, BLASTER_VEN_REQ_RESET, 2, 1, 0);
+    assert!(dev.control_out(&mut [&mut blaster], req, &[]).is_err());
+    assert!(!blaster.pulse_nsrst().unwrap());
+    assert_eq!(*levels.borrow(), [true, false, true]);
+}
+
+/// Writes the three lines together, counting the writes
+struct MaskedLines<TDI, TCK, TMS> {
+    tdi: TDI,
+    tck: TCK,
+    tms: TMS,
+    writes: Rc<Cell<u32>>,
+}
+
+impl<TDI, TCK, TMS> MaskedWrite<Infallible> for MaskedLines<TDI, TCK, TMS>
+where
+    TDI: OutputPin<Error = Infallible>,
+    TCK: OutputPin<Error = Infallible>,
+    TMS: OutputPin<Error = Infallible>,
+{
+    fn write_lines(&mut self, tdi: bool, tck: bool, tms: bool) -> Result<(), Infallible> {
+        self.writes.set(self.writes.get() + 1);
+        if tdi {
+            self.tdi.set_high()?;
+        } else {
+            self.tdi.set_low()?;
+        }
+        if tms {
+            self.tms.set_high()?;
+        } else {
+            self.tms.set_low()?;
+        }
+        if tck {
+            self.tck.set_high()
+        } else {
+            self.tck.set_low()
+        }
+    }
+}
+
+#[test]
+fn reads_idcodes_with_masked_writes() {
+    let chain = JtagChain::new(vec![
+        TapDevice::new(0x1234_5679, 10, 0x006),
+        TapDevice::new(0x0ABC_DEF1, 4, 0x1),
+    ]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let (masked_tdi, masked_tck, masked_tms, _) = chain.pins();
+    let writes = Rc::new(Cell::new(0));
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_masked_write(MaskedLines {
+        tdi: masked_tdi,
+        tck: masked_tck,
+        tms: masked_tms,
+        writes: writes.clone(),
+    });
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+    writes.set(0);
+
+    let mut bytes = reset_to_shift_dr();
+    bytes.push(0xC8);
+    bytes.extend_from_slice(&[0; 8]);
+    let reply = exchange(&mut dev, &mut blaster, &bytes);
+
+    let mut expected = MODEM_STATUS.to_vec();
+    expected.extend_from_slice(&0x0ABC_DEF1u32.to_le_bytes());
+    expected.extend_from_slice(&0x1234_5679u32.to_le_bytes());
+    assert_eq!(reply, expected);
+    // One write per bit-bang byte, and per shifted byte one to set up the first bit and two per bit
+    assert_eq!(writes.get(), 19 + 8 * 17);
+}
+
+/// A backend that records what it is asked to do, and answers shifts with the bits inverted
+#[derive(Default)]
+struct Recording {
+    bangs: Vec<u8>,
+    shifts: Vec<(Vec<u8>, bool)>,
+    resets: usize,
+}
+
+impl JtagBackend for Recording {
+    type Error = Infallible;
+
+    fn bit_bang(&mut self, byte: u8) -> Result<(), BlasterError<Infallible>> {
+        self.bangs.push(byte);
+        Ok(())
+    }
+
+    fn read_status(&mut self) -> Result<u8, BlasterError<Infallible>> {
+        Ok(0x01)
+    }
+
+    fn shift_byte(&mut self, byte: u8, _read: bool) -> Result<u8, BlasterError<Infallible>> {
+        Ok(!byte)
+    }
+
+    fn shift_bytes(&mut self, data: &mut [u8], read: bool) -> Result<(), BlasterError<Infallible>> {
+        self.shifts.push((data.to_vec(), read));
+        for byte in data.iter_mut() {
+            *byte = !*byte;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Infallible> {
+        self.resets += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn hands_bytes_to_custom_backend() {
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::from_backend(&alloc, Recording::default());
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+    let resets = blaster.backend().resets;
+    assert!(resets >= 1);
+
+    // A read bit-bang byte, three read shift bytes, two written shift bytes, then bit-bang again
+    let reply = exchange(
+        &mut dev,
+        &mut blaster,
+        &[0x41, 0xC3, 0x01, 0x02, 0x03, 0x82, 0xAA, 0x55, 0x20],
+    );
+    assert_eq!(reply[2..], [0x01, 0xFE, 0xFD, 0xFC]);
+    assert_eq!(blaster.backend().bangs, [0x41, 0x20]);
+    assert_eq!(
+        blaster.backend().shifts,
+        [(vec![0x01, 0x02, 0x03], true), (vec![0xAA, 0x55], false)]
+    );
+
+    // No TCK frequency limit to set
+    let req = vendor_request(UsbDirection::Out, BLASTER_VEN_REQ_SET_TCK_FREQ, 1000, 0, 0);
+    assert!(dev.control_out(&mut [&mut blaster], req, &[]).is_err());
+    assert!(matches!(
+        blaster.take_error(),
+        Some(BlasterError::InvalidRequest(BLASTER_VEN_REQ_SET_TCK_FREQ))
+    ));
+
+    dev.host_reset(&mut [&mut blaster]);
+    assert!(blaster.backend().resets > resets);
+}
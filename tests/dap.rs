@@ -0,0 +1,272 @@
+//! Drives a `CmsisDap` through a `MockBus`, with a simulated JTAG chain on its pins.
+
+use core::convert::Infallible;
+use std::cell::Cell;
+use std::rc::Rc;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::prelude::*;
+use usb_device::UsbDirection;
+use usbd_blaster::testing::{
+    JtagChain, MockBus, MockHost, SimTck, SimTdi, SimTdo, SimTms, TapDevice,
+};
+use usbd_blaster::{CmsisDap, Driven, ResetPins, TckDelay};
+
+type SimDap<'a, D, RS = ()> = CmsisDap<
+    'a,
+    MockBus,
+    Infallible,
+    Driven<SimTdi>,
+    Driven<SimTck>,
+    Driven<SimTms>,
+    SimTdo,
+    D,
+    RS,
+>;
+
+/// Bulk endpoint number of both the request and response endpoints
+const EP: u8 = 1;
+
+const ID_DAP_CONNECT: u8 = 0x02;
+const ID_DAP_DELAY: u8 = 0x09;
+const ID_DAP_RESET_TARGET: u8 = 0x0A;
+const ID_DAP_SWJ_PINS: u8 = 0x10;
+const ID_DAP_SWJ_SEQUENCE: u8 = 0x12;
+const ID_DAP_JTAG_CONFIGURE: u8 = 0x15;
+const ID_DAP_JTAG_IDCODE: u8 = 0x16;
+const DAP_OK: u8 = 0x00;
+const DAP_ERROR: u8 = 0xFF;
+
+/// Adds up the time waited, without waiting
+struct Elapsed(Rc<Cell<u64>>);
+
+impl TckDelay for Elapsed {
+    fn delay_ns(&mut self, ns: u32) {
+        self.0.set(self.0.get() + ns as u64);
+    }
+}
+
+/// Only nSRST is connected. Records its level and how often it was asserted.
+struct SystemReset {
+    asserted: Rc<Cell<bool>>,
+    pulses: Rc<Cell<u32>>,
+}
+
+impl ResetPins<Infallible> for SystemReset {
+    fn set_ntrst(&mut self, _asserted: bool) -> Result<bool, Infallible> {
+        Ok(false)
+    }
+
+    fn set_nsrst(&mut self, asserted: bool) -> Result<bool, Infallible> {
+        if asserted && !self.asserted.get() {
+            self.pulses.set(self.pulses.get() + 1);
+        }
+        self.asserted.set(asserted);
+        Ok(true)
+    }
+}
+
+/// Fetch a descriptor of the given type and index, as the host does while enumerating
+fn descriptor<'a, D: TckDelay>(
+    dev: &mut UsbDevice<'a, MockBus>,
+    dap: &mut SimDap<'a, D>,
+    descriptor_type: u8,
+    index: u8,
+    lang_id: u16,
+) -> Vec<u8> {
+    const GET_DESCRIPTOR: u8 = 0x06;
+    let req = Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: GET_DESCRIPTOR,
+        value: (descriptor_type as u16) << 8 | index as u16,
+        index: lang_id,
+        length: 255,
+    };
+    dev.control_in(&mut [dap], req).unwrap()
+}
+
+/// The body of each interface descriptor in a configuration descriptor
+fn interfaces(configuration: &[u8]) -> Vec<Vec<u8>> {
+    const INTERFACE: u8 = 0x04;
+    let mut interfaces = Vec::new();
+    let mut offset = 0;
+    while offset < configuration.len() {
+        let len = configuration[offset] as usize;
+        if configuration[offset + 1] == INTERFACE {
+            interfaces.push(configuration[offset + 2..offset + len].to_vec());
+        }
+        offset += len;
+    }
+    interfaces
+}
+
+/// Send one request and take the response
+fn command<'a, D: TckDelay, RS: ResetPins<Infallible>>(
+    dev: &mut UsbDevice<'a, MockBus>,
+    dap: &mut SimDap<'a, D, RS>,
+    request: &[u8],
+) -> Vec<u8> {
+    dev.bus().bulk_out(EP, request);
+    dap.poll(dev).unwrap();
+    dev.bus().bulk_in(EP).unwrap()
+}
+
+#[test]
+fn delay_waits() {
+    let elapsed = Rc::new(Cell::new(0));
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut dap = CmsisDap::new(&alloc, tdi, tck, tms, tdo).with_delay(Elapsed(elapsed.clone()));
+    let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0xC251, 0xF001)).build();
+    dev.host_reset(&mut [&mut dap]);
+
+    let before = elapsed.get();
+    assert_eq!(
+        command(&mut dev, &mut dap, &[ID_DAP_DELAY, 0xE8, 0x03]),
+        [ID_DAP_DELAY, DAP_OK]
+    );
+    assert_eq!(elapsed.get() - before, 1_000_000);
+}
+
+#[test]
+fn drives_reset_lines() {
+    const NTRST: u8 = 0x20;
+    const NRESET: u8 = 0x80;
+    let elapsed = Rc::new(Cell::new(0));
+    let asserted = Rc::new(Cell::new(false));
+    let pulses = Rc::new(Cell::new(0));
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut dap = CmsisDap::new(&alloc, tdi, tck, tms, tdo)
+        .with_delay(Elapsed(elapsed.clone()))
+        .with_reset_pins(SystemReset {
+            asserted: asserted.clone(),
+            pulses: pulses.clone(),
+        });
+    let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0xC251, 0xF001)).build();
+    dev.host_reset(&mut [&mut dap]);
+
+    // nRESET reads back low while asserted, and the unconnected nTRST stays high
+    let response = command(
+        &mut dev,
+        &mut dap,
+        &[ID_DAP_SWJ_PINS, 0, NTRST | NRESET, 0, 0, 0, 0],
+    );
+    assert_eq!(response[0], ID_DAP_SWJ_PINS);
+    assert_eq!(response[1] & (NTRST | NRESET), NTRST);
+    assert!(asserted.get());
+
+    // nTRST never follows, so the whole wait runs out
+    let before = elapsed.get();
+    let response = command(
+        &mut dev,
+        &mut dap,
+        &[ID_DAP_SWJ_PINS, NRESET, NTRST | NRESET, 0xE8, 0x03, 0, 0],
+    );
+    assert_eq!(response[1] & (NTRST | NRESET), NTRST | NRESET);
+    assert!(!asserted.get());
+    assert!(elapsed.get() - before >= 1_000_000);
+
+    // The target is reset with a 1 ms pulse on nSRST
+    let before = elapsed.get();
+    assert_eq!(
+        command(&mut dev, &mut dap, &[ID_DAP_RESET_TARGET]),
+        [ID_DAP_RESET_TARGET, DAP_OK, 1]
+    );
+    assert_eq!(pulses.get(), 2);
+    assert!(!asserted.get());
+    assert_eq!(elapsed.get() - before, 1_000_000);
+}
+
+#[test]
+fn configures_chain() {
+    let chain = JtagChain::new(vec![
+        TapDevice::new(0x1234_5679, 10, 0x006),
+        TapDevice::new(0x4BA0_0477, 4, 0x0E),
+    ]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut dap = CmsisDap::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0xC251, 0xF001)).build();
+    dev.host_reset(&mut [&mut dap]);
+
+    command(&mut dev, &mut dap, &[ID_DAP_CONNECT, 0]);
+    // Test-Logic-Reset, then Run-Test/Idle
+    command(&mut dev, &mut dap, &[ID_DAP_SWJ_SEQUENCE, 8, 0x7F]);
+
+    // An IR length of 0 is rejected, leaving the chain as it was
+    assert_eq!(
+        command(&mut dev, &mut dap, &[ID_DAP_JTAG_CONFIGURE, 2, 4, 0]),
+        [ID_DAP_JTAG_CONFIGURE, DAP_ERROR]
+    );
+    assert_eq!(
+        command(&mut dev, &mut dap, &[ID_DAP_JTAG_IDCODE, 1]),
+        [ID_DAP_JTAG_IDCODE, DAP_ERROR]
+    );
+
+    // The device nearest TDO comes first
+    assert_eq!(
+        command(&mut dev, &mut dap, &[ID_DAP_JTAG_CONFIGURE, 2, 4, 10]),
+        [ID_DAP_JTAG_CONFIGURE, DAP_OK]
+    );
+    let mut expected = vec![ID_DAP_JTAG_IDCODE, DAP_OK];
+    expected.extend_from_slice(&0x4BA0_0477u32.to_le_bytes());
+    assert_eq!(
+        command(&mut dev, &mut dap, &[ID_DAP_JTAG_IDCODE, 0]),
+        expected
+    );
+}
+
+#[test]
+fn names_interface_for_hosts() {
+    const CONFIGURATION: u8 = 0x02;
+    const STRING: u8 = 0x03;
+    const EN_US: u16 = 0x0409;
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut dap = CmsisDap::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0xC251, 0xF001)).build();
+    dev.host_reset(&mut [&mut dap]);
+
+    // One interface, with the string on alternate setting 1 and the same two endpoints on both settings
+    let configuration = descriptor(&mut dev, &mut dap, CONFIGURATION, 0, 0);
+    assert_eq!(configuration[4], 1);
+    let settings = interfaces(&configuration);
+    assert_eq!(settings.len(), 2);
+    assert_eq!(settings[0][..5], [0, 0, 2, 0xFF, 0]);
+    assert_eq!(settings[1][..5], [0, 1, 2, 0xFF, 0]);
+    let interface_string = settings[1][6];
+    assert_ne!(interface_string, 0);
+
+    let string = descriptor(&mut dev, &mut dap, STRING, interface_string, EN_US);
+    let expected: Vec<u8> = "CMSIS-DAP"
+        .encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect();
+    assert_eq!(string[2..], expected[..]);
+
+    // The host may select the named setting, which works the same
+    let req = Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Interface,
+        request: Request::SET_INTERFACE,
+        value: 1,
+        index: 0,
+        length: 0,
+    };
+    dev.control_out(&mut [&mut dap], req, &[]).unwrap();
+    command(&mut dev, &mut dap, &[ID_DAP_CONNECT, 0]);
+    let req = Request {
+        direction: UsbDirection::In,
+        request: Request::GET_INTERFACE,
+        length: 1,
+        ..req
+    };
+    assert_eq!(dev.control_in(&mut [&mut dap], req).unwrap(), [1]);
+}
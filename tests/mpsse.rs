@@ -0,0 +1,88 @@
+//! Drives an `Mpsse` through a `MockBus`, with a simulated JTAG chain on its pins.
+
+use usb_device::bus::UsbBusAllocator;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::prelude::*;
+use usb_device::UsbDirection;
+use usbd_blaster::testing::{JtagChain, MockBus, MockHost, TapDevice};
+use usbd_blaster::{checksum, Mpsse, EEPROM_SIZE, FTDI_FT2232H_USB_VID_PID};
+
+/// Bulk OUT endpoint carrying commands from the host
+const EP_OUT: u8 = 2;
+/// Bulk IN endpoint carrying replies to the host
+const EP_IN: u8 = 1;
+
+const FTDI_VEN_REQ_RD_EEPROM: u8 = 0x90;
+const FTDI_VEN_REQ_SET_BITMODE: u8 = 0x0B;
+const BITMODE_MPSSE: u16 = 0x0200;
+
+fn vendor_request(
+    direction: UsbDirection,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+) -> Request {
+    Request {
+        direction,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request,
+        value,
+        index,
+        length,
+    }
+}
+
+#[test]
+fn serves_ft2232h_eeprom() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut mpsse = Mpsse::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, FTDI_FT2232H_USB_VID_PID)
+        .device_release(0x0700)
+        .build();
+    dev.host_reset(&mut [&mut mpsse]);
+
+    let mut image = [0u8; EEPROM_SIZE];
+    for word in 0..EEPROM_SIZE / 2 {
+        let req = vendor_request(UsbDirection::In, FTDI_VEN_REQ_RD_EEPROM, 0, word as u16, 2);
+        let data = dev.control_in(&mut [&mut mpsse], req).unwrap();
+        image[2 * word..2 * word + 2].copy_from_slice(&data);
+    }
+    assert_eq!(image[2..8], [0x03, 0x04, 0x10, 0x60, 0x00, 0x07]);
+    // A 93C46
+    assert_eq!(image[0x18], 0x46);
+    assert_eq!(
+        u16::from_le_bytes([image[EEPROM_SIZE - 2], image[EEPROM_SIZE - 1]]),
+        checksum(&image)
+    );
+}
+
+#[test]
+fn replies_to_bad_commands() {
+    let chain = JtagChain::new(vec![TapDevice::bypass_only(4)]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut mpsse = Mpsse::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, FTDI_FT2232H_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut mpsse]);
+
+    let req = vendor_request(
+        UsbDirection::Out,
+        FTDI_VEN_REQ_SET_BITMODE,
+        BITMODE_MPSSE,
+        0,
+        0,
+    );
+    dev.control_out(&mut [&mut mpsse], req, &[]).unwrap();
+
+    // An unknown opcode, then GET_BITS_HIGH to show the engine carries on
+    dev.bus().bulk_out(EP_OUT, &[0xAB, 0x83]);
+    mpsse.poll(&mut dev).unwrap();
+    assert_eq!(
+        dev.bus().bulk_in(EP_IN).unwrap(),
+        [0x01, 0xC0, 0xFA, 0xAB, 0x00]
+    );
+}
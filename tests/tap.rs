@@ -0,0 +1,118 @@
+//! Checks the simulated TAP chain on its own and through a `Blaster`.
+
+use core::convert::Infallible;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use usb_device::bus::UsbBusAllocator;
+use usb_device::prelude::*;
+use usbd_blaster::testing::{
+    JtagChain, MockBus, MockHost, SimTck, SimTdi, SimTdo, SimTms, TapDevice, TapState,
+};
+use usbd_blaster::{Blaster, Driven, Port, ALTERA_BLASTER_USB_VID_PID};
+
+type SimBlaster<'a> =
+    Blaster<'a, MockBus, Port<Infallible, Driven<SimTdi>, Driven<SimTck>, Driven<SimTms>, SimTdo>>;
+
+/// Bit-bang output enable
+const OE: u8 = 0x20;
+/// Bit-bang TDI high
+const TDI: u8 = 0x10;
+/// Bit-bang TMS high
+const TMS: u8 = 0x02;
+/// Bit-bang TCK high
+const TCK: u8 = 0x01;
+
+/// Bit-bang bytes for one TCK pulse with the given TMS and TDI levels
+fn pulse(tms: bool, tdi: bool) -> [u8; 2] {
+    let mut byte = OE;
+    if tms {
+        byte |= TMS;
+    }
+    if tdi {
+        byte |= TDI;
+    }
+    [byte, byte | TCK]
+}
+
+/// Bit-bang bytes that clock TMS through `tms` with TDI low, leaving TCK low
+fn clock_tms(tms: &[bool]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = tms.iter().flat_map(|&high| pulse(high, false)).collect();
+    bytes.push(OE);
+    bytes
+}
+
+fn exchange(dev: &mut UsbDevice<MockBus>, blaster: &mut SimBlaster, bytes: &[u8]) -> Vec<u8> {
+    dev.bus().bulk_out(2, bytes);
+    dev.poll(&mut [blaster]);
+    blaster.read().unwrap();
+    blaster.handle().unwrap();
+    blaster.write().unwrap();
+    // Drop the modem status
+    dev.bus().bulk_in(1).unwrap().split_off(2)
+}
+
+#[test]
+fn follows_state_machine() {
+    let chain = JtagChain::new(vec![TapDevice::bypass_only(4), TapDevice::bypass_only(6)]);
+    let (mut tdi, mut tck, mut tms, tdo) = chain.pins();
+    tdi.set_low().unwrap();
+    let mut clock = |high: bool| {
+        if high {
+            tms.set_high().unwrap();
+        } else {
+            tms.set_low().unwrap();
+        }
+        tck.set_high().unwrap();
+        tck.set_low().unwrap();
+    };
+
+    for _ in 0..5 {
+        clock(true);
+    }
+    assert_eq!(chain.state(0), TapState::TestLogicReset);
+    for &high in &[false, true, true, false, false] {
+        clock(high);
+    }
+    assert_eq!(chain.state(0), TapState::ShiftIR);
+    assert_eq!(chain.state(1), TapState::ShiftIR);
+    assert_eq!(chain.rising_edges(), 10);
+    // The last device captured 0b01 and drives its LSB
+    assert!(tdo.is_high().unwrap());
+    clock(false);
+    assert!(tdo.is_low().unwrap());
+}
+
+#[test]
+fn captures_ir_and_loads_bypass() {
+    let chain = JtagChain::new(vec![
+        TapDevice::new(0x1234_5679, 10, 0x006),
+        TapDevice::new(0x0ABC_DEF1, 4, 0x1),
+    ]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // Test-Logic-Reset, then Shift-IR
+    let mut bytes = clock_tms(&[
+        true, true, true, true, true, false, true, true, false, false,
+    ]);
+    // Shift and read 2 bytes of ones
+    bytes.extend_from_slice(&[0xC2, 0xFF, 0xFF]);
+    let captured = exchange(&mut dev, &mut blaster, &bytes);
+    // 0b0001 from the 4 bit IR, 0b0000000001 from the 10 bit IR, then the first ones shifted in
+    assert_eq!(u16::from_le_bytes([captured[0], captured[1]]), 0xC011);
+
+    // The last one goes in on the way to Exit1-IR, then Update-IR latches BYPASS
+    let mut bytes = pulse(true, true).to_vec();
+    bytes.extend(clock_tms(&[true, false]));
+    exchange(&mut dev, &mut blaster, &bytes);
+    assert_eq!(chain.device(0).ir(), 0x3FF);
+    assert_eq!(chain.device(1).ir(), 0xF);
+    assert_eq!(chain.state(0), TapState::RunTestIdle);
+
+    // Each BYPASS register delays the data by one bit and captures 0
+    let mut bytes = clock_tms(&[true, false, false]);
+    bytes.extend_from_slice(&[0xC1, 0xA5]);
+    assert_eq!(exchange(&mut dev, &mut blaster, &bytes), [0x94]);
+}
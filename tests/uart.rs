@@ -0,0 +1,248 @@
+//! Bridges a mock serial peripheral to the host through a `Blaster` on a `MockBus`.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::prelude::*;
+use usb_device::UsbDirection;
+use usbd_blaster::testing::{JtagChain, MockBus, MockHost, TapDevice};
+use usbd_blaster::{
+    Blaster, BlasterError, LineCoding, Parity, Uart, UartConfig, ALTERA_BLASTER_USB_VID_PID,
+};
+
+/// Bulk OUT endpoint carrying bytes for the JTAG side
+const EP_JTAG_OUT: u8 = 2;
+/// Bulk IN endpoint carrying bytes from the JTAG side
+const EP_JTAG_IN: u8 = 1;
+/// Bulk OUT endpoint carrying bytes for the UART
+const EP_UART_OUT: u8 = 1;
+/// Bulk IN endpoint carrying bytes from the UART
+const EP_UART_IN: u8 = 2;
+/// The modem status at the start of every IN packet
+const MODEM_STATUS: [u8; 2] = [0x01, 0xC0];
+
+/// Interface of the UART, after the JTAG interface
+const UART_IFACE: u16 = 1;
+
+const FTDI_VEN_REQ_RESET: u8 = 0x00;
+const FTDI_VEN_REQ_SET_BAUDRATE: u8 = 0x03;
+const FTDI_VEN_REQ_SET_DATA_CHAR: u8 = 0x04;
+
+/// A serial peripheral with scripted received bytes, where `None` is a framing error
+struct MockSerial {
+    rx: VecDeque<Option<u8>>,
+    tx: Rc<RefCell<Vec<u8>>>,
+    /// Fail to send this byte
+    tx_fails_on: Option<u8>,
+}
+
+impl embedded_hal::serial::Read<u8> for MockSerial {
+    type Error = ();
+
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        match self.rx.pop_front() {
+            Some(Some(byte)) => Ok(byte),
+            Some(None) => Err(nb::Error::Other(())),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+impl embedded_hal::serial::Write<u8> for MockSerial {
+    type Error = ();
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+        if self.tx_fails_on == Some(byte) {
+            return Err(nb::Error::Other(()));
+        }
+        self.tx.borrow_mut().push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl UartConfig for MockSerial {
+    fn set_line_coding(&mut self, coding: &LineCoding) -> bool {
+        coding.data_bits == 8
+    }
+}
+
+fn vendor_request(request: u8, value: u16, index: u16) -> Request {
+    Request {
+        direction: UsbDirection::Out,
+        request_type: RequestType::Vendor,
+        recipient: Recipient::Device,
+        request,
+        value,
+        index,
+        length: 0,
+    }
+}
+
+/// The interface numbers in a configuration descriptor
+fn interfaces(descriptor: &[u8]) -> Vec<u8> {
+    const INTERFACE: u8 = 0x04;
+    let mut numbers = Vec::new();
+    let mut offset = 0;
+    while offset < descriptor.len() {
+        if descriptor[offset + 1] == INTERFACE {
+            numbers.push(descriptor[offset + 2]);
+        }
+        offset += descriptor[offset] as usize;
+    }
+    numbers
+}
+
+fn configuration_descriptor(
+    dev: &mut UsbDevice<MockBus>,
+    classes: &mut [&mut dyn UsbClass<MockBus>],
+) -> Vec<u8> {
+    const GET_DESCRIPTOR: u8 = 0x06;
+    const CONFIGURATION: u16 = 0x02;
+    let req = Request {
+        direction: UsbDirection::In,
+        request_type: RequestType::Standard,
+        recipient: Recipient::Device,
+        request: GET_DESCRIPTOR,
+        value: CONFIGURATION << 8,
+        index: 0,
+        length: 255,
+    };
+    dev.control_in(classes, req).unwrap()
+}
+
+#[test]
+fn describes_uart_on_its_own_interface() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo);
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+    assert_eq!(
+        interfaces(&configuration_descriptor(&mut dev, &mut [&mut blaster])),
+        [0]
+    );
+
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let serial = MockSerial {
+        rx: VecDeque::new(),
+        tx: Rc::default(),
+        tx_fails_on: None,
+    };
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_uart(&alloc, Uart::new(serial));
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+    assert_eq!(
+        interfaces(&configuration_descriptor(&mut dev, &mut [&mut blaster])),
+        [0, 1]
+    );
+}
+
+#[test]
+fn keeps_line_coding_accepted_by_uart() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let serial = MockSerial {
+        rx: VecDeque::new(),
+        tx: Rc::default(),
+        tx_fails_on: None,
+    };
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_uart(&alloc, Uart::new(serial));
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // 115200 baud has a divisor of 26
+    let req = vendor_request(FTDI_VEN_REQ_SET_BAUDRATE, 26, UART_IFACE);
+    dev.control_out(&mut [&mut blaster], req, &[]).unwrap();
+    // 8 data bits, odd parity
+    let req = vendor_request(FTDI_VEN_REQ_SET_DATA_CHAR, 0x0108, UART_IFACE);
+    dev.control_out(&mut [&mut blaster], req, &[]).unwrap();
+    // 7 data bits are rejected by the UART, and the previous settings kept
+    let req = vendor_request(FTDI_VEN_REQ_SET_DATA_CHAR, 0x0007, UART_IFACE);
+    assert!(dev.control_out(&mut [&mut blaster], req, &[]).is_err());
+    // The JTAG interface has no line settings
+    let req = vendor_request(FTDI_VEN_REQ_SET_BAUDRATE, 13, 0);
+    assert!(dev.control_out(&mut [&mut blaster], req, &[]).is_err());
+    assert_eq!(blaster.line_coding().baud_rate, 115_384);
+    assert_eq!(blaster.line_coding().data_bits, 8);
+    assert_eq!(blaster.line_coding().parity, Parity::Odd);
+}
+
+#[test]
+fn reports_dropped_bytes() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let tx = Rc::new(RefCell::new(Vec::new()));
+    let serial = MockSerial {
+        rx: vec![Some(b'o'), None, Some(b'k')].into(),
+        tx: tx.clone(),
+        tx_fails_on: Some(b'!'),
+    };
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_uart(&alloc, Uart::new(serial));
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // The bytes before each error go through, then the error is returned
+    dev.bus().bulk_out(EP_UART_OUT, b"hi!yo");
+    assert!(matches!(blaster.poll(&mut dev), Err(BlasterError::Uart)));
+    assert_eq!(
+        dev.bus().bulk_in(EP_UART_IN).unwrap(),
+        [MODEM_STATUS[0], MODEM_STATUS[1], b'o']
+    );
+
+    // The damaged bytes are gone and the rest follow
+    blaster.poll(&mut dev).unwrap();
+    assert_eq!(
+        dev.bus().bulk_in(EP_UART_IN).unwrap(),
+        [MODEM_STATUS[0], MODEM_STATUS[1], b'k']
+    );
+    assert_eq!(tx.borrow()[..], b"hiyo"[..]);
+}
+
+#[test]
+fn resets_only_the_uart_channel() {
+    let chain = JtagChain::new(vec![TapDevice::new(0x1234_5679, 10, 0x006)]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let alloc = UsbBusAllocator::new(MockBus::new());
+    let serial = MockSerial {
+        rx: VecDeque::new(),
+        tx: Rc::default(),
+        tx_fails_on: None,
+    };
+    let mut blaster = Blaster::new(&alloc, tdi, tck, tms, tdo).with_uart(&alloc, Uart::new(serial));
+    let mut dev = UsbDeviceBuilder::new(&alloc, ALTERA_BLASTER_USB_VID_PID).build();
+    dev.host_reset(&mut [&mut blaster]);
+
+    // Go through Test-Logic-Reset to Shift-DR, and announce 4 bytes to shift and read
+    let mut bytes = Vec::new();
+    for &tms in &[true, true, true, true, true, false, true, false, false] {
+        let tms_bit = if tms { 0x02 } else { 0x00 };
+        bytes.extend_from_slice(&[0x20 | tms_bit, 0x21 | tms_bit]);
+    }
+    bytes.extend_from_slice(&[0x20, 0xC4]);
+    dev.bus().bulk_out(EP_JTAG_OUT, &bytes);
+    blaster.poll(&mut dev).unwrap();
+    assert_eq!(dev.bus().bulk_in(EP_JTAG_IN).unwrap(), MODEM_STATUS);
+
+    const RESET_SIO: u16 = 0x0000;
+    let req = vendor_request(FTDI_VEN_REQ_RESET, RESET_SIO, UART_IFACE);
+    dev.control_out(&mut [&mut blaster], req, &[]).unwrap();
+
+    // The JTAG side still expects the 4 bytes, and the TAP is still in Shift-DR
+    dev.bus().bulk_out(EP_JTAG_OUT, &[0; 4]);
+    blaster.poll(&mut dev).unwrap();
+    let mut expected = MODEM_STATUS.to_vec();
+    expected.extend_from_slice(&0x1234_5679u32.to_le_bytes());
+    assert_eq!(dev.bus().bulk_in(EP_JTAG_IN).unwrap(), expected);
+}
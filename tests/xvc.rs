@@ -0,0 +1,79 @@
+//! Serves XVC commands from an in-memory stream, with a simulated JTAG chain on the pins.
+
+use std::io::{Read, Result, Write};
+use usbd_blaster::testing::{JtagChain, TapDevice};
+use usbd_blaster::{Xvc, XVC_MAX_VECTOR_LEN};
+
+/// Hands out the bytes from the host as fast as they are read, and collects the replies
+struct Stream {
+    input: Vec<u8>,
+    read: usize,
+    output: Vec<u8>,
+}
+
+impl Stream {
+    fn new(input: Vec<u8>) -> Self {
+        Stream {
+            input,
+            read: 0,
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let amount = buf.len().min(self.input.len() - self.read);
+        buf[..amount].copy_from_slice(&self.input[self.read..self.read + amount]);
+        self.read += amount;
+        Ok(amount)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn reports_max_vector_len() {
+    let chain = JtagChain::new(vec![]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let mut xvc = Xvc::new(tdi, tck, tms, tdo);
+
+    let mut stream = Stream::new(b"getinfo:".to_vec());
+    xvc.serve(&mut stream).unwrap();
+    assert_eq!(
+        stream.output,
+        format!("xvcServer_v1.0:{}\n", XVC_MAX_VECTOR_LEN).as_bytes()
+    );
+}
+
+#[test]
+fn replies_to_pipelined_commands() {
+    let chain = JtagChain::new(vec![TapDevice::bypass_only(4)]);
+    let (tdi, tck, tms, tdo) = chain.pins();
+    let mut xvc = Xvc::new(tdi, tck, tms, tdo);
+    let info = format!("xvcServer_v1.0:{}\n", XVC_MAX_VECTOR_LEN);
+
+    // A shift that fills the read buffer on its own. TMS stays low, so TDO is never driven.
+    let bits = 8 * XVC_MAX_VECTOR_LEN as u32;
+    let mut input = b"shift:".to_vec();
+    input.extend_from_slice(&bits.to_le_bytes());
+    input.extend(vec![0; 2 * XVC_MAX_VECTOR_LEN]);
+    // The replies to a full read buffer of `getinfo:` take several writes, all before the host closes the stream
+    input.extend(b"getinfo:".repeat(40));
+    let mut stream = Stream::new(input);
+    xvc.serve(&mut stream).unwrap();
+
+    let mut expected = vec![0xFF; XVC_MAX_VECTOR_LEN];
+    expected.extend(info.repeat(40).into_bytes());
+    assert_eq!(stream.output, expected);
+    assert_eq!(chain.rising_edges(), bits as usize);
+}